@@ -1,8 +1,12 @@
 #![allow(unused)]
 
+use core::cell::Cell;
+
+use cortex_m::interrupt::Mutex;
 use stm32h7::stm32h753::Peripherals;
 
 use crate::device_access::with_devices_mut;
+use crate::time::micros_with_devices;
 
 pub fn init() {
     with_devices_mut(|devices, _| {
@@ -35,4 +39,75 @@ pub fn set_with_devices(devices: &mut Peripherals, state: bool) {
     devices.GPIOC.odr.modify(|_, w| {
         w.odr13().bit(!state)
     });
-}
\ No newline at end of file
+}
+
+/*
+    Status patterns, so the board's state can be read off the LED without a controller attached. The
+    pattern is worked out from time::micros() whenever update() is called, so it only animates as
+    often as the main loop gets around to calling it.
+
+    - Idle: a short flash every two seconds
+    - Locking: fast blinking
+    - Running: solid on
+    - Overcurrent: a double flash every second
+    - Fault: SOS, repeating
+    - LockFailed(n): n brief flashes every two seconds, with n saying why the last burst failed to lock
+*/
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum StatusCode {
+    Idle,
+    Locking,
+    Running,
+    Overcurrent,
+    Fault,
+    LockFailed(u8),
+}
+
+// the lock failure flashes are kept brief, so they can't be mistaken for the overcurrent double flash
+const LOCK_FAILED_FLASH_MS: u64 = 40;
+const LOCK_FAILED_FLASH_SPACING_MS: u64 = 250;
+
+// SOS in morse, one bit per 150ms unit, first unit in the lowest bit: dots are one unit on, dashes three,
+// with a unit off between them, three between letters, and seven before it repeats
+const SOS_UNIT_MS: u64 = 150;
+const SOS_UNITS: u64 = 34;
+const SOS_PATTERN: u64 = 0b101_0100_0111_0111_0111_0001_0101;
+
+static STATUS: Mutex<Cell<StatusCode>> = Mutex::new(Cell::new(StatusCode::Idle));
+
+pub fn set_status(status: StatusCode) {
+    with_devices_mut(|devices, _| {
+        set_status_with_devices(devices, status);
+    })
+}
+
+pub fn set_status_with_devices(devices: &mut Peripherals, status: StatusCode) {
+    cortex_m::interrupt::free(|cs| STATUS.borrow(cs).set(status));
+    update_with_devices(devices);
+}
+
+pub fn update() {
+    with_devices_mut(|devices, _| {
+        update_with_devices(devices);
+    })
+}
+
+pub fn update_with_devices(devices: &mut Peripherals) {
+    let status = cortex_m::interrupt::free(|cs| STATUS.borrow(cs).get());
+    let ms = micros_with_devices(devices) / 1000;
+    let state = match status {
+        StatusCode::Idle => ms % 2000 < 200,
+        StatusCode::Locking => ms % 100 < 50,
+        StatusCode::Running => true,
+        StatusCode::Overcurrent => {
+            let phase = ms % 1000;
+            phase < 100 || (200..300).contains(&phase)
+        },
+        StatusCode::Fault => (SOS_PATTERN >> ((ms / SOS_UNIT_MS) % SOS_UNITS)) & 1 != 0,
+        StatusCode::LockFailed(flashes) => {
+            let phase = ms % 2000;
+            phase / LOCK_FAILED_FLASH_SPACING_MS < flashes as u64 && phase % LOCK_FAILED_FLASH_SPACING_MS < LOCK_FAILED_FLASH_MS
+        },
+    };
+    set_with_devices(devices, state);
+}
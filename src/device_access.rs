@@ -33,24 +33,41 @@ pub fn set_devices(devices: stm32h753::Peripherals) {
         w.hrtimrst().clear_bit()
     });
 
-    // enable and reset GPIOA, GPIOC, GPIOD, and SYSCFG
+    // enable and reset ADC1/2 (ADC3 is in the AHB4 group below), all clocked from per_ck
+    devices.RCC.d3ccipr.modify(|_, w| {
+        w.adcsel().per()
+    });
+    devices.RCC.ahb1enr.modify(|_, w| {
+        w.adc12en().set_bit()
+    });
+    devices.RCC.ahb1rstr.write(|w| {
+        w.adc12rst().set_bit()
+    });
+    devices.RCC.ahb1rstr.write(|w| {
+        w.adc12rst().clear_bit()
+    });
+
+    // enable and reset GPIOA, GPIOC, GPIOD, ADC3, and SYSCFG
     devices.RCC.ahb4enr.modify(|_, w| {
         w
             .gpioaen().set_bit()
             .gpiocen().set_bit()
             .gpioden().set_bit()
+            .adc3en().set_bit()
     });
     devices.RCC.ahb4rstr.write(|w| {
         w
             .gpioarst().set_bit()
             .gpiocrst().set_bit()
             .gpiodrst().set_bit()
+            .adc3rst().set_bit()
     });
     devices.RCC.ahb4rstr.write(|w| {
         w
             .gpioarst().clear_bit()
             .gpiocrst().clear_bit()
             .gpiodrst().clear_bit()
+            .adc3rst().clear_bit()
     });
 
     // if we're not already in VOS1, let's get there
@@ -0,0 +1,80 @@
+use core::cell::Cell;
+
+use cortex_m::interrupt::Mutex;
+
+use crate::qcw;
+use crate::reset_cause::{self, ResetCause};
+
+/*
+Figures about how the controller is doing that don't drive any decisions, gathered into one static so
+they can be read with a debugger while it runs (`print DIAGNOSTICS` in gdb). The main loop calls update
+after every burst, and records the rest as it goes.
+*/
+
+// the fields are only ever read by the debugger
+#[allow(dead_code)]
+#[derive(Copy, Clone, Debug)]
+pub struct Diagnostics {
+    // 0-100 score of how steady the feedback period was at the end of the last burst
+    pub lock_quality: u8,
+    // captures the filter has dropped as glitches since boot
+    pub capture_rejections: u32,
+    // captures and signal path configs rejected for a period under qcw::MIN_PERIOD_CLOCKS since boot
+    pub short_period_rejections: u32,
+    // why the MCU last reset, so a watchdog reset can be told apart from a power cycle
+    pub reset_cause: ResetCause,
+    // the feedback frequency the last burst to lock locked to, and how long after the burst started it did
+    pub lock_frequency_khz: f32,
+    pub lock_latency_us: u32,
+    // the last heatsink and MCU die temperatures read, None if the read failed, and the power derating
+    // factor they set
+    pub heatsink_temperature_c: Option<f32>,
+    pub die_temperature_c: Option<f32>,
+    pub thermal_derating: f32,
+}
+
+#[no_mangle]
+static DIAGNOSTICS: Mutex<Cell<Diagnostics>> = Mutex::new(Cell::new(Diagnostics {
+    lock_quality: 0,
+    capture_rejections: 0,
+    short_period_rejections: 0,
+    reset_cause: ResetCause::Unknown,
+    lock_frequency_khz: 0.0,
+    lock_latency_us: 0,
+    heatsink_temperature_c: None,
+    die_temperature_c: None,
+    thermal_derating: 1.0,
+}));
+
+fn modify<F: FnOnce(&mut Diagnostics)>(f: F) {
+    cortex_m::interrupt::free(|cs| {
+        let cell = DIAGNOSTICS.borrow(cs);
+        let mut diagnostics = cell.get();
+        f(&mut diagnostics);
+        cell.set(diagnostics);
+    });
+}
+
+pub fn update() {
+    modify(|diagnostics| {
+        diagnostics.lock_quality = qcw::lock_quality();
+        diagnostics.capture_rejections = qcw::capture_rejections();
+        diagnostics.short_period_rejections = qcw::short_period_rejections();
+        diagnostics.reset_cause = reset_cause::last();
+    });
+}
+
+pub fn record_lock(period_clocks: u16, latency_us: u64) {
+    modify(|diagnostics| {
+        diagnostics.lock_frequency_khz = qcw::period_clocks_to_khz(period_clocks);
+        diagnostics.lock_latency_us = latency_us.min(u32::MAX as u64) as u32;
+    });
+}
+
+pub fn record_temperatures(heatsink_c: Option<f32>, die_c: Option<f32>, derating: f32) {
+    modify(|diagnostics| {
+        diagnostics.heatsink_temperature_c = heatsink_c;
+        diagnostics.die_temperature_c = die_c;
+        diagnostics.thermal_derating = derating;
+    });
+}
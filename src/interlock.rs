@@ -0,0 +1,54 @@
+#![allow(unused)]
+
+use stm32h7::stm32h753::Peripherals;
+
+use crate::device_access::with_devices_mut;
+use crate::time::micros_with_devices;
+
+/*
+External Interlock
+------------------
+
+GPIO D0 is the external interlock input (door switch, e-stop loop, etc). The loop shorts the pin to
+ground when closed, and the internal pull-up pulls it high when open, so a broken wire reads as open.
+*/
+
+// how long the input has to read steadily open before we believe it, so a noise spike doesn't trip it
+const DEBOUNCE_US: u64 = 2_000;
+
+pub fn init() {
+    with_devices_mut(|devices, _| {
+        init_with_devices(devices)
+    })
+}
+
+pub fn init_with_devices(devices: &mut Peripherals) {
+    devices.GPIOD.pupdr.modify(|_, w| {
+        w.pupdr0().pull_up()
+    });
+    devices.GPIOD.moder.modify(|_, w| {
+        w.moder0().input()
+    });
+}
+
+pub fn is_closed() -> bool {
+    with_devices_mut(|devices, _| {
+        is_closed_with_devices(devices)
+    })
+}
+
+/*
+    Reports closed as soon as the input reads closed, and only reports open once it has read open
+    continuously for DEBOUNCE_US. The input is always read at least once, whatever the time does.
+*/
+pub fn is_closed_with_devices(devices: &mut Peripherals) -> bool {
+    let t0 = micros_with_devices(devices);
+    loop {
+        if devices.GPIOD.idr.read().idr0().bit_is_clear() {
+            return true;
+        }
+        if micros_with_devices(devices).saturating_sub(t0) >= DEBOUNCE_US {
+            return false;
+        }
+    }
+}
@@ -1,29 +1,205 @@
-#![no_main]
-#![no_std]
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(not(test), no_std)]
 
+#[cfg(not(test))]
 extern crate panic_halt;
+// host tests build against std, which doesn't bring core into scope by itself
+#[cfg(test)]
+extern crate core;
 extern crate cortex_m_rt;
 extern crate cortex_m;
 extern crate stm32h7;
+extern crate libm;
 
 use core::u16;
 
+#[cfg(not(test))]
 use cortex_m_rt::entry;
 use device_access::{set_devices, with_devices_mut};
 use pll_setup::{setup_system_pll, switch_cpu_to_system_pll};
 use stm32h7::stm32h753;
-use time::{block_micros, block_millis};
+use time::block_micros;
 
 mod pll_setup;
 mod time;
 mod device_access;
 mod debug_led;
 mod qcw;
+mod temp_monitor;
+mod watchdog;
+mod interlock;
+mod reset_cause;
+mod diagnostics;
 
-#[entry]
+// run everything except the bridge outputs, for bench testing the control logic with no gate drive
+const OUTPUT_INHIBIT: bool = false;
+
+// the bridge always gets at least this long to cool down between bursts, whatever the burst settings say
+const MIN_OFF_TIME_US: u64 = 10_000;
+
+// how far past the expected end of a burst the hardware on-time backstop allows before forcing the outputs idle.
+// The software timing ends every burst well before this, so the backstop only ever fires if the burst loop
+// stalls. A burst configured longer than qcw::ABSOLUTE_MAX_ON_TIME_US is cut short there by the backstop,
+// and trips it.
+const ON_TIME_BACKSTOP_MARGIN_US: u64 = 100;
+
+// fraction of the target conduction angle a soft start begins at
+const SOFT_START_INITIAL_FRACTION: f32 = 0.05;
+
+// burst settings
+const STARTUP_TIME_US: u64 = 60;
+const TOTAL_TIME_US: u64 = 400;
+const STARTUP_PERIOD: u16 = 666;
+const PERIOD_OFFSET_MAX: u16 = 100;
+
+// how far the feedback frequency may sit below and above the startup frequency and still lock. The
+// defaults accept anything below the startup frequency and nothing above it.
+const LOCK_RANGE_BELOW_KHZ: f32 = f32::INFINITY;
+const LOCK_RANGE_ABOVE_KHZ: f32 = 0.0;
+
+// once locked, the lock range is widened by this much either side, so a frequency hovering at the
+// edge of the range doesn't drop lock, and the burst is only aborted after LOCK_LOSS_CYCLES captures
+// in a row outside it. Zero cycles disables lock loss detection.
+const LOCK_HOLD_MARGIN_KHZ: f32 = 20.0;
+const LOCK_LOSS_CYCLES: u32 = 8;
+
+// consecutive bursts that fail to lock before we stop firing until reset, or 0 to keep trying forever
+const MAX_LOCK_ATTEMPTS: u32 = 10;
+
+// number of captures the lock decision takes the median of
+const CAPTURE_FILTER_LENGTH: usize = 5;
+
+// captures further than this from the running median are dropped as glitches, or 0 to keep them all
+const CAPTURE_MAX_DEVIATION_PERCENT: u16 = 20;
+
+// number of feedback cycles between bridge phase flips, kept even so each leg leads equally
+const PHASE_FLIP_CYCLES: u16 = 4;
+
+const _: () = assert!(
+    PHASE_FLIP_CYCLES.is_multiple_of(2) && PHASE_FLIP_CYCLES >= 2 && PHASE_FLIP_CYCLES <= 256,
+    "PHASE_FLIP_CYCLES must be even, and within what timer b's repetition counter can count"
+);
+
+// how long closed loop can go without a feedback capture before we decide the coil has quenched
+const FEEDBACK_TIMEOUT_US: u64 = 20;
+
+// heatsink temperature above which we stop firing bursts until reset
+const THERMAL_LIMIT_C: f32 = 80.0;
+
+// control board MCU die temperature above which we stop firing bursts until reset
+const DIE_THERMAL_LIMIT_C: f32 = 85.0;
+
+// above these temperatures the closed loop power is scaled down linearly, reaching zero at the limits above
+const THERMAL_DERATE_START_C: f32 = 65.0;
+const DIE_THERMAL_DERATE_START_C: f32 = 75.0;
+
+// number of bursts to fire before stopping, or None to keep going forever
+const BURST_COUNT: Option<u32> = None;
+const OFF_TIME_MS: u64 = 100;
+
+// if non-zero, the burst rate in breaks per second, which sets the off time in place of OFF_TIME_MS
+const BREAKS_PER_SECOND: u64 = 0;
+
+// the most of the time the bridge may spend switching, averaged over a burst and its off time
+const MAX_DUTY_PERCENT: u64 = 5;
+
+// the startup frequency is swept outward from STARTUP_PERIOD, alternating either side of it, out to
+// +/- SWEEP_RANGE_CLOCKS over SWEEP_STEPS steps, dwelling SWEEP_DWELL_US at each until the feedback settles
+// within SWEEP_MATCH_CLOCKS of the drive period. If no step settles, the startup goes back to STARTUP_PERIOD.
+// One step disables the sweep, which is the default until the settle test has been proven on a coil.
+const SWEEP_STEPS: u16 = 1;
+const SWEEP_DWELL_US: u64 = 20;
+const SWEEP_RANGE_CLOCKS: u16 = 60;
+const SWEEP_MATCH_CLOCKS: u16 = 10;
+const OPEN_LOOP_CONDUCTION_ANGLE: f32 = 0.3;
+
+// number of cycles over which the open loop conduction angle ramps up at burst start, so we don't
+// hard switch full power into a cold coil. Zero disables the soft start.
+const SOFT_START_CYCLES: u64 = 16;
+
+// once locked, the closed loop conduction angle ramps from start to end over CLOSED_LOOP_RAMP_US
+const CLOSED_LOOP_START_ANGLE: f32 = 0.1;
+const CLOSED_LOOP_END_ANGLE: f32 = 0.5;
+const CLOSED_LOOP_RAMP_US: u64 = TOTAL_TIME_US - STARTUP_TIME_US;
+const CLOSED_LOOP_POWER_SHAPE: PowerShape = PowerShape::Linear;
+
+// the phase setpoint sweeps from start to end over the closed loop part of the burst, shifting both legs'
+// triggers together. Positive is later than the feedback zero crossing, negative earlier.
+const PHASE_SWEEP_START_DEG: f32 = 0.0;
+const PHASE_SWEEP_END_DEG: f32 = 0.0;
+
+// fixed compensation for the propagation delay through the feedback and gate drive, pulling both legs'
+// triggers earlier by this much on top of the phase setpoint. Negative values push them later instead.
+const DELAY_COMPENSATION_NS: i16 = 0;
+
+// per-leg closed loop trigger trims in HRTIM clocks, for evening out gate drive propagation between the legs
+const PHASE_TRIM_A_CLOCKS: i16 = 0;
+const PHASE_TRIM_C_CLOCKS: i16 = 0;
+
+// the conduction angle tapers to zero over the last TAIL_RAMP_US of the burst, rather than cutting off
+// abruptly. This is inside TOTAL_TIME_US, so it doesn't lengthen the burst. Zero disables it.
+const TAIL_RAMP_US: u64 = 20;
+
+// the closed loop power is scaled up over this many bursts after boot, so the first bursts into a
+// cold coil don't go straight to full power. Zero disables it.
+const SOFT_START_BURSTS: u64 = 0;
+
+// limits on the settings above, checked at compile time so a bad value can't quietly misbehave on the bench
+const _: () = assert!(STARTUP_TIME_US < TOTAL_TIME_US, "the open loop startup has to leave time in the burst to lock");
+const _: () = assert!(
+    STARTUP_PERIOD >= qcw::MIN_PERIOD_CLOCKS + SWEEP_RANGE_CLOCKS,
+    "the startup sweep must stay above qcw::MIN_PERIOD_CLOCKS, or open loop is disabled instead of run"
+);
+const _: () = assert!(
+    CAPTURE_FILTER_LENGTH >= 1 && CAPTURE_FILTER_LENGTH <= qcw::CAPTURE_HISTORY_LENGTH,
+    "the capture filter can't take the median of more captures than qcw keeps"
+);
+const _: () = assert!(
+    OPEN_LOOP_CONDUCTION_ANGLE >= 0.0 && OPEN_LOOP_CONDUCTION_ANGLE <= 1.0
+        && CLOSED_LOOP_START_ANGLE >= 0.0 && CLOSED_LOOP_START_ANGLE <= 1.0
+        && CLOSED_LOOP_END_ANGLE >= 0.0 && CLOSED_LOOP_END_ANGLE <= 1.0,
+    "conduction angles are fractions, from 0 to 1"
+);
+
+// nothing kicks the watchdog during a burst, so its timeout is sized around the longest burst allowed
+const _: () = assert!(
+    TOTAL_TIME_US + ON_TIME_BACKSTOP_MARGIN_US <= qcw::ABSOLUTE_MAX_ON_TIME_US as u64,
+    "a burst must fit within qcw::ABSOLUTE_MAX_ON_TIME_US, or the watchdog can reset mid-burst"
+);
+
+// how the closed loop conduction angle gets from CLOSED_LOOP_START_ANGLE to CLOSED_LOOP_END_ANGLE. Only the
+// one CLOSED_LOOP_POWER_SHAPE picks is ever built.
+#[allow(dead_code)]
+#[derive(Copy, Clone, Debug)]
+enum PowerShape {
+    // a straight line over CLOSED_LOOP_RAMP_US
+    Linear,
+    // rising quickly at first and then settling onto the end angle, with time constant tau_us
+    Exponential { tau_us: u64 },
+    // (microseconds since lock, conduction angle) points, in place of the start and end angles
+    Table(&'static [(u32, f32)]),
+}
+
+// why a burst never made it to closed loop, or didn't stay there
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum LockFailure {
+    // no feedback captures at all, so the feedback wiring or the coil itself is at fault
+    NoFeedback,
+    // feedback is there, but never settled within the lock range of the startup frequency
+    FrequencyMismatch,
+    // locked, but the feedback frequency then drifted out of the hold range mid-burst
+    LockLost,
+    // locked, but then the feedback stopped altogether mid-burst, so the coil quenched or the feedback failed
+    FeedbackLost,
+}
+
+#[cfg_attr(not(test), entry)]
 fn main() -> ! {
     set_devices(stm32h753::Peripherals::take().unwrap());
 
+    // before anything else, so nothing in init can muddy the flags
+    reset_cause::init();
+
     with_devices_mut(|devices, _| {
         setup_system_pll(devices, pll_setup::SystemPllSpeed::MHz400);
         switch_cpu_to_system_pll(devices);
@@ -32,6 +208,15 @@ fn main() -> ! {
     debug_led::init();
     time::init();
     qcw::init();
+    temp_monitor::init();
+    watchdog::init();
+    interlock::init();
+    // so the reset cause can be read before the first burst
+    diagnostics::update();
+
+    if OUTPUT_INHIBIT {
+        with_devices_mut(|devices, _| qcw::set_output_inhibit(devices, true));
+    }
 
     unsafe { cortex_m::interrupt::enable() };
 
@@ -39,48 +224,184 @@ fn main() -> ! {
 
     let mut zero_angle = 0.05f32;
 
+    let mut thermal_tripped = false;
+    let mut overcurrent_tripped = false;
+    let mut interlock_tripped = false;
+    let mut on_time_tripped = false;
+    let mut failed_lock_attempts: u32 = 0;
+    let mut bursts_fired: u32 = 0;
+
     loop {
-        let STARTUP_TIME_US: u64 = 60;
-        let TOTAL_TIME_US: u64 = 400;
-        let STARTUP_PERIOD: u16 = 666;
-        let PERIOD_OFFSET_MAX: u16 = 100;
+        watchdog::kick();
 
+        // both temperatures are read every time round, even once tripped, so the diagnostics stay current
+        let temperature = with_devices_mut(|devices, _| temp_monitor::get_temperature_c(devices, &temp_monitor::DEFAULT_THERMISTOR));
+        let die_temperature = with_devices_mut(|devices, _| temp_monitor::get_die_temperature(devices));
+        // whichever of the two temperatures is further into its derating range sets the power scale
+        let mut thermal_derating = 1.0f32;
+        if !thermal_tripped {
+            // if we can't read the temperature, we can't know it's safe to run
+            thermal_tripped = match temperature {
+                Some(temperature) => {
+                    thermal_derating = thermal_derating.min(derating_factor(temperature, THERMAL_DERATE_START_C, THERMAL_LIMIT_C));
+                    temperature > THERMAL_LIMIT_C
+                },
+                None => true,
+            };
+        }
+        if !thermal_tripped {
+            thermal_tripped = match die_temperature {
+                Some(die_temperature) => {
+                    thermal_derating = thermal_derating.min(derating_factor(die_temperature, DIE_THERMAL_DERATE_START_C, DIE_THERMAL_LIMIT_C));
+                    die_temperature > DIE_THERMAL_LIMIT_C
+                },
+                None => true,
+            };
+        }
+        diagnostics::record_temperatures(temperature, die_temperature, thermal_derating);
+        // the hardware has already turned the bridge off, but don't keep firing into whatever tripped it
+        if !overcurrent_tripped {
+            overcurrent_tripped = with_devices_mut(|devices, _| qcw::overcurrent_fault(devices));
+        }
+        // once the interlock has been opened, stay stopped until reset rather than starting back up on our own
+        if !interlock_tripped {
+            interlock_tripped = !interlock::is_closed();
+        }
+        let sequence_complete = matches!(BURST_COUNT, Some(count) if bursts_fired >= count);
+        // after too many failed locks in a row, stop rather than keep hammering a mistuned or disconnected coil
+        let lock_gave_up = MAX_LOCK_ATTEMPTS != 0 && failed_lock_attempts >= MAX_LOCK_ATTEMPTS;
+        if thermal_tripped || overcurrent_tripped || interlock_tripped || on_time_tripped || lock_gave_up || sequence_complete {
+            with_devices_mut(|devices, _| {
+                qcw::configure_signal_path(devices, qcw::SignalPathConfig::Disabled);
+                qcw::set_outputs_enabled(devices, false);
+                // running out of bursts is just the end of the sequence, not a fault
+                let status = if overcurrent_tripped {
+                    debug_led::StatusCode::Overcurrent
+                } else if thermal_tripped || interlock_tripped || on_time_tripped || lock_gave_up {
+                    debug_led::StatusCode::Fault
+                } else {
+                    debug_led::StatusCode::Idle
+                };
+                debug_led::set_status_with_devices(devices, status);
+            });
+            block_micros_showing_status(100_000);
+            continue;
+        }
+
+        // work out the off time before starting, so a too-short one is lengthened before we commit to the burst
+        let off_time_us = if BREAKS_PER_SECOND != 0 {
+            bps_off_time_us(BREAKS_PER_SECOND, TOTAL_TIME_US)
+        } else {
+            OFF_TIME_MS * 1000
+        };
+        // this also effectively clamps the BPS down if it would put the duty cycle over the limit
+        let off_time_us = duty_limited_off_time_us(TOTAL_TIME_US, off_time_us, MAX_DUTY_PERCENT);
+
+        // scale this burst's closed loop power by where it falls in the soft start, and by the thermal derating
+        let burst_power_scale = soft_start_angle(1.0, bursts_fired as u64, SOFT_START_BURSTS) * thermal_derating;
+        bursts_fired = bursts_fired.saturating_add(1);
+        debug_led::set_status(debug_led::StatusCode::Locking);
         feedback_values.fill(0);
+        qcw::reset_capture_filter();
+        // if the loop below stalls, the hardware still won't let the bridge run much past the end of the burst
+        with_devices_mut(|devices, _| {
+            qcw::arm_on_time_backstop(devices, (TOTAL_TIME_US + ON_TIME_BACKSTOP_MARGIN_US) as u32);
+            qcw::set_cycle_event_interval(devices, PHASE_FLIP_CYCLES);
+        });
         let t0 = time::micros();
-        with_devices_mut(|devices, _| qcw::configure_signal_path(devices, qcw::SignalPathConfig::OpenLoop { period_clocks: STARTUP_PERIOD, conduction_angle: 0.3 }));
+        let mut sweep_step = 0;
+        let mut startup_period = STARTUP_PERIOD;
+        let mut t_step = t0;
+        let mut sweeping = SWEEP_STEPS > 1;
+        // the soft start has to be done by the time we try for lock, so it never runs past the startup time
+        let soft_start_us = (SOFT_START_CYCLES * STARTUP_PERIOD as u64 / qcw::HRTIM_CLOCKS_PER_US).min(STARTUP_TIME_US);
+        let mut conduction_angle = soft_start_angle(OPEN_LOOP_CONDUCTION_ANGLE, 0, soft_start_us);
+        with_devices_mut(|devices, _| qcw::configure_signal_path(devices, qcw::SignalPathConfig::OpenLoop { period_clocks: startup_period, conduction_angle }));
+        let delay_comp_clocks = qcw::ns_to_clocks(DELAY_COMPENSATION_NS);
+        let leg_trims = qcw::LegTrims { a: PHASE_TRIM_A_CLOCKS, c: PHASE_TRIM_C_CLOCKS };
         
-        // spend some time in open loop mode to ring up the primary
+        // spend some time in open loop mode to ring up the primary, sweeping the frequency until the
+        // feedback settles onto the drive period, and then staying at whatever frequency got it there
         loop {
             let now = time::micros();
             if now - t0 >= STARTUP_TIME_US {
                 break;
             }
+            if conduction_angle < OPEN_LOOP_CONDUCTION_ANGLE {
+                conduction_angle = soft_start_angle(OPEN_LOOP_CONDUCTION_ANGLE, now - t0, soft_start_us);
+                with_devices_mut(|devices, _| qcw::set_open_loop_conduction_angle(devices, startup_period, conduction_angle));
+            }
+            if !sweeping {
+                continue;
+            }
+            let settled = with_devices_mut(|devices, _| {
+                if let Some(value) = qcw::read_capture_timer_filtered(devices, CAPTURE_FILTER_LENGTH, CAPTURE_MAX_DEVIATION_PERCENT) {
+                    push_feedback_value(&mut feedback_values, value);
+                    return sweep_settled(startup_period, SWEEP_MATCH_CLOCKS, &feedback_values[..]);
+                }
+                false
+            });
+            if settled {
+                sweeping = false;
+            } else if now - t_step >= SWEEP_DWELL_US {
+                sweep_step += 1;
+                t_step = now;
+                // nothing along the sweep matched, so fall back to the tuned frequency rather than the end of the sweep
+                if sweep_step >= SWEEP_STEPS {
+                    sweeping = false;
+                    startup_period = STARTUP_PERIOD;
+                } else {
+                    startup_period = sweep_period(STARTUP_PERIOD, SWEEP_RANGE_CLOCKS, SWEEP_STEPS, sweep_step);
+                }
+                feedback_values.fill(0);
+                qcw::reset_capture_filter();
+                with_devices_mut(|devices, _| qcw::configure_signal_path(devices, qcw::SignalPathConfig::OpenLoop { period_clocks: startup_period, conduction_angle }));
+            }
         }
 
-        // then try and lock the loop
+        // then try and lock the loop, within the lock range around the tuned startup frequency, wherever the
+        // sweep ended up, so the sweep can't widen what we'll lock to
+        let (lock_min_period, lock_max_period) = lock_window(STARTUP_PERIOD, LOCK_RANGE_BELOW_KHZ, LOCK_RANGE_ABOVE_KHZ, 0.0);
+        let (hold_min_period, hold_max_period) = lock_window(STARTUP_PERIOD, LOCK_RANGE_BELOW_KHZ, LOCK_RANGE_ABOVE_KHZ, LOCK_HOLD_MARGIN_KHZ);
+        let mut lock_failure = None;
+        let mut power_profile = qcw::ClosedLoopPowerProfile::Constant(CLOSED_LOOP_START_ANGLE);
+        let mut phase_profile = qcw::PhaseProfile::Fixed(PHASE_SWEEP_START_DEG);
         loop {
             let now = time::micros();
             if now - t0 >= TOTAL_TIME_US {
-                with_devices_mut(|devices, _| {
+                lock_failure = Some(with_devices_mut(|devices, _| {
                     qcw::configure_signal_path(devices, qcw::SignalPathConfig::Disabled);
-                    debug_led::set_with_devices(devices, false);
-                });
+                    debug_led::set_status_with_devices(devices, debug_led::StatusCode::Idle);
+                    if qcw::feedback_alive(devices, FEEDBACK_TIMEOUT_US) {
+                        LockFailure::FrequencyMismatch
+                    } else {
+                        LockFailure::NoFeedback
+                    }
+                }));
                 break;
             }
             let closed_loop = with_devices_mut(|devices, _| {
-                if let Some(value) = qcw::read_capture_timer(devices) {
-                    for i in (1..feedback_values.len()).rev() {
-                        feedback_values[i] = feedback_values[i - 1];
-                    }
-                    feedback_values[0] = value;
-                    if feedback_variance_acceptable(PERIOD_OFFSET_MAX, STARTUP_PERIOD, &feedback_values[..]) {
-                        debug_led::set_with_devices(devices, true);
+                if let Some(value) = qcw::read_capture_timer_filtered(devices, CAPTURE_FILTER_LENGTH, CAPTURE_MAX_DEVIATION_PERCENT) {
+                    push_feedback_value(&mut feedback_values, value);
+                    if feedback_variance_acceptable(PERIOD_OFFSET_MAX, lock_min_period, lock_max_period, &feedback_values[..]) {
+                        debug_led::set_status_with_devices(devices, debug_led::StatusCode::Running);
                         let mut feedback_value_total = 0;
                         for v in feedback_values.iter() {
                             feedback_value_total += *v as u32;
                         }
                         feedback_value_total /= feedback_values.len() as u32;
-                        qcw::configure_signal_path(devices, qcw::SignalPathConfig::ClosedLoop { period_clocks: feedback_value_total as u16, conduction_angle: 0.5, zero_angle, delay_comp: 0 });
+                        power_profile = closed_loop_power_profile(CLOSED_LOOP_POWER_SHAPE, now);
+                        phase_profile = qcw::PhaseProfile::Sweep {
+                            start_deg: PHASE_SWEEP_START_DEG,
+                            end_deg: PHASE_SWEEP_END_DEG,
+                            t_start: now,
+                            t_sweep: CLOSED_LOOP_RAMP_US,
+                        };
+                        let period_clocks = feedback_value_total as u16;
+                        diagnostics::record_lock(period_clocks, now - t0);
+                        let conduction_angle = qcw::power_at(&power_profile, now) * burst_power_scale;
+                        let phase_offset = qcw::degrees_to_clocks(period_clocks, qcw::phase_at(&phase_profile, now)).saturating_sub(delay_comp_clocks);
+                        qcw::configure_signal_path(devices, qcw::SignalPathConfig::ClosedLoop { period_clocks, conduction_angle, zero_angle, phase_offset, flip_phases: false, leg_trims });
                         return true
                     }
                 }
@@ -89,36 +410,344 @@ fn main() -> ! {
             if closed_loop {
                 break;
             }
+            // no point waiting out the rest of the burst for a lock that can't happen
+            let feedback_dead = with_devices_mut(|devices, _| !qcw::feedback_alive(devices, FEEDBACK_TIMEOUT_US));
+            if feedback_dead {
+                with_devices_mut(|devices, _| {
+                    qcw::configure_signal_path(devices, qcw::SignalPathConfig::Disabled);
+                    debug_led::set_status_with_devices(devices, debug_led::StatusCode::Idle);
+                });
+                lock_failure = Some(LockFailure::NoFeedback);
+                break;
+            }
         };
 
-        // now we're in closed loop
+        // now we're in closed loop, so start counting flip cycles from here rather than from the open loop startup
+        let mut flip_phases = false;
+        let mut out_of_range_cycles = 0;
+        with_devices_mut(|devices, _| qcw::take_cycle_event(devices));
         loop {
+            if lock_failure.is_some() {
+                break;
+            }
             let now = time::micros();
             if now - t0 >= TOTAL_TIME_US {
                 with_devices_mut(|devices, _| {
                     qcw::configure_signal_path(devices, qcw::SignalPathConfig::Disabled);
-                    debug_led::set_with_devices(devices, false);
+                    debug_led::set_status_with_devices(devices, debug_led::StatusCode::Idle);
                 });
                 break;
             }
+            // if feedback has stopped, the output timers will already have stopped retriggering, so
+            // just make it official rather than continuing to drive a dead capture value
+            let feedback_dead = with_devices_mut(|devices, _| !qcw::feedback_alive(devices, FEEDBACK_TIMEOUT_US));
+            if feedback_dead {
+                with_devices_mut(|devices, _| {
+                    qcw::configure_signal_path(devices, qcw::SignalPathConfig::Disabled);
+                    debug_led::set_status_with_devices(devices, debug_led::StatusCode::Idle);
+                });
+                lock_failure = Some(LockFailure::FeedbackLost);
+                break;
+            }
             with_devices_mut(|devices, _| {
-                if let Some(value) = qcw::read_capture_timer(devices) {
-                    qcw::configure_signal_path(devices, qcw::SignalPathConfig::ClosedLoop { period_clocks: value, conduction_angle: 0.5, zero_angle, delay_comp: 0 });
+                if let Some(value) = qcw::read_capture_timer_filtered(devices, CAPTURE_FILTER_LENGTH, CAPTURE_MAX_DEVIATION_PERCENT) {
+                    if value > hold_min_period && value < hold_max_period {
+                        out_of_range_cycles = 0;
+                    } else {
+                        out_of_range_cycles += 1;
+                        if LOCK_LOSS_CYCLES != 0 && out_of_range_cycles >= LOCK_LOSS_CYCLES {
+                            qcw::configure_signal_path(devices, qcw::SignalPathConfig::Disabled);
+                            debug_led::set_status_with_devices(devices, debug_led::StatusCode::Idle);
+                            lock_failure = Some(LockFailure::LockLost);
+                            return;
+                        }
+                    }
+                    // timer b counts the switching cycles, so a capture dropped by the filter doesn't skew the flips
+                    if qcw::take_cycle_event(devices) {
+                        flip_phases = !flip_phases;
+                    }
+                    // re-evaluate the power profile every cycle, so ramps actually ramp
+                    let conduction_angle = qcw::power_at(&power_profile, now) * burst_power_scale * tail_ramp_factor(now - t0, TOTAL_TIME_US, TAIL_RAMP_US);
+                    let phase_offset = qcw::degrees_to_clocks(value, qcw::phase_at(&phase_profile, now)).saturating_sub(delay_comp_clocks);
+                    qcw::configure_signal_path(devices, qcw::SignalPathConfig::ClosedLoop { period_clocks: value, conduction_angle, zero_angle, phase_offset, flip_phases, leg_trims });
                 }
             });
         }
-        with_devices_mut(|devices, _| qcw::configure_signal_path(devices, qcw::SignalPathConfig::Disabled));
+        // a burst long enough to trip the backstop means the timing in here has gone wrong, so stop until reset
+        on_time_tripped = with_devices_mut(|devices, _| {
+            qcw::configure_signal_path(devices, qcw::SignalPathConfig::Disabled);
+            let tripped = qcw::on_time_backstop_tripped(devices);
+            qcw::disarm_on_time_backstop(devices);
+            tripped
+        });
+        let t_burst_end = time::micros();
+        diagnostics::update();
+
+        // a lock that was acquired and then lost still counts as a lock, since the coil did respond
+        failed_lock_attempts = match lock_failure {
+            Some(LockFailure::NoFeedback) | Some(LockFailure::FrequencyMismatch) => failed_lock_attempts + 1,
+            Some(LockFailure::LockLost) | Some(LockFailure::FeedbackLost) | None => 0,
+        };
 
-        block_millis(100);
+        // report a failed lock on the LED through the off time, and on until a burst gets through, so a wiring
+        // fault can be told apart from a tuning problem. The pattern runs while the off time waits, so it
+        // never lengthens the off time.
+        debug_led::set_status(match lock_failure {
+            Some(failure) => debug_led::StatusCode::LockFailed(lock_failure_flashes(failure)),
+            None => debug_led::StatusCode::Idle,
+        });
+
+        // check the duty cycle again against how long the burst actually ran, in case it overran the
+        // configured on time
+        let off_time_us = duty_limited_off_time_us(t_burst_end - t0, off_time_us, MAX_DUTY_PERCENT);
+        block_micros_showing_status(off_time_us.max(MIN_OFF_TIME_US).saturating_sub(time::micros() - t_burst_end));
+    }
+}
+
+// like block_micros, but keeps the debug LED's status pattern going and the watchdog fed while it waits
+fn block_micros_showing_status(n: u64) {
+    let t0 = time::micros();
+    while time::micros() - t0 < n {
+        watchdog::kick();
+        debug_led::update();
+        block_micros(n.saturating_sub(time::micros() - t0).min(1000));
     }
 }
 
-fn feedback_variance_acceptable(allowed_deviation: u16, min_period: u16, feedback_values: &[u16]) -> bool {
+// how many times the debug LED flashes to report a lock failure
+fn lock_failure_flashes(failure: LockFailure) -> u8 {
+    match failure {
+        LockFailure::NoFeedback => 1,
+        LockFailure::FrequencyMismatch => 2,
+        LockFailure::LockLost => 3,
+        LockFailure::FeedbackLost => 4,
+    }
+}
+
+// the closed loop power profile for a burst that locked at t_start, before any soft start or derating scaling
+fn closed_loop_power_profile(shape: PowerShape, t_start: u64) -> qcw::ClosedLoopPowerProfile {
+    match shape {
+        PowerShape::Linear => qcw::ClosedLoopPowerProfile::Ramp {
+            start: CLOSED_LOOP_START_ANGLE,
+            end: CLOSED_LOOP_END_ANGLE,
+            t_start,
+            t_ramp: CLOSED_LOOP_RAMP_US,
+        },
+        PowerShape::Exponential { tau_us } => qcw::ClosedLoopPowerProfile::Exponential {
+            start: CLOSED_LOOP_START_ANGLE,
+            end: CLOSED_LOOP_END_ANGLE,
+            t_start,
+            tau: tau_us,
+        },
+        PowerShape::Table(points) => qcw::ClosedLoopPowerProfile::Table { points, t_start },
+    }
+}
+
+// the off time that gives a burst rate of bps, given the burst's on time
+fn bps_off_time_us(bps: u64, on_time_us: u64) -> u64 {
+    (1_000_000 / bps.max(1)).saturating_sub(on_time_us)
+}
+
+/*
+    Returns the off time to use after a burst of on_time_us, lengthening off_time_us if needed so that
+    on / (on + off) doesn't exceed max_duty_percent. Integer math throughout, rounding the off time up.
+*/
+fn duty_limited_off_time_us(on_time_us: u64, off_time_us: u64, max_duty_percent: u64) -> u64 {
+    if max_duty_percent >= 100 {
+        return off_time_us;
+    }
+    let max_duty_percent = max_duty_percent.max(1);
+    let min_off_time_us = (on_time_us * (100 - max_duty_percent)).div_ceil(max_duty_percent);
+    off_time_us.max(min_off_time_us)
+}
+
+// the conduction angle at elapsed into a soft start ramp of length ramp, starting from a small fraction of
+// target. elapsed and ramp can be in any unit, as long as it's the same one.
+fn soft_start_angle(target: f32, elapsed: u64, ramp: u64) -> f32 {
+    if elapsed >= ramp {
+        return target;
+    }
+    let fraction = SOFT_START_INITIAL_FRACTION + (1.0 - SOFT_START_INITIAL_FRACTION) * (elapsed as f32 / ramp as f32);
+    target * fraction
+}
+
+// 1 at or below start_c, falling linearly to 0 at limit_c
+fn derating_factor(temperature_c: f32, start_c: f32, limit_c: f32) -> f32 {
+    if temperature_c <= start_c {
+        return 1.0;
+    }
+    if limit_c <= start_c {
+        return 0.0;
+    }
+    (1.0 - (temperature_c - start_c) / (limit_c - start_c)).max(0.0)
+}
+
+// scales power down linearly to zero over the last tail_us of a burst. Faults don't go through this, they
+// disable the signal path straight away.
+fn tail_ramp_factor(elapsed_us: u64, total_us: u64, tail_us: u64) -> f32 {
+    let remaining_us = total_us.saturating_sub(elapsed_us);
+    if tail_us == 0 || remaining_us >= tail_us {
+        return 1.0;
+    }
+    remaining_us as f32 / tail_us as f32
+}
+
+fn push_feedback_value(feedback_values: &mut [u16], value: u16) {
+    for i in (1..feedback_values.len()).rev() {
+        feedback_values[i] = feedback_values[i - 1];
+    }
+    feedback_values[0] = value;
+}
+
+/*
+    The open loop period for a given step of the startup sweep. Step 0 is center, and the steps after it
+    alternate longer and shorter, moving outward evenly so the last step on each side is center +/- range.
+*/
+fn sweep_period(center: u16, range: u16, steps: u16, step: u16) -> u16 {
+    if steps <= 1 || step == 0 {
+        return center;
+    }
+    // the furthest step out on either side
+    let max_distance = steps as u32 / 2;
+    let distance = step.div_ceil(2) as u32;
+    let offset = (range as u32 * distance / max_distance) as u16;
+    if step % 2 == 1 {
+        center.saturating_add(offset)
+    } else {
+        center.saturating_sub(offset)
+    }
+}
+
+/*
+    True once the feedback has settled onto the drive period. The feedback rings at the coil's own
+    resonance, so away from it the captures wander or sit at a different period from the drive, and only
+    a drive near resonance gives steady captures within match of drive_period.
+*/
+fn sweep_settled(drive_period: u16, match_clocks: u16, feedback_values: &[u16]) -> bool {
+    feedback_variance_acceptable(
+        match_clocks,
+        drive_period.saturating_sub(match_clocks),
+        drive_period.saturating_add(match_clocks),
+        feedback_values,
+    )
+}
+
+/*
+    The shortest and longest feedback periods, exclusive, that count as in lock around center_period. A
+    higher frequency is a shorter period, so the range above sets the shortest period and the range below
+    the longest. margin_khz widens the window on both sides.
+*/
+fn lock_window(center_period: u16, below_khz: f32, above_khz: f32, margin_khz: f32) -> (u16, u16) {
+    let center_khz = qcw::period_clocks_to_khz(center_period);
+    let min_period = qcw::khz_to_period_clocks(center_khz + above_khz + margin_khz);
+    let max_period = qcw::khz_to_period_clocks(center_khz - below_khz - margin_khz);
+    (min_period, max_period)
+}
+
+// true if every feedback period is strictly between min_period and max_period, and they're all within
+// allowed_deviation of each other
+fn feedback_variance_acceptable(allowed_deviation: u16, min_period: u16, max_period: u16, feedback_values: &[u16]) -> bool {
     let mut min = u16::MAX;
     let mut max = u16::MIN;
     for v in feedback_values.iter() {
         min = min.min(*v);
         max = max.max(*v);
     }
-    min > min_period && (max - min) < allowed_deviation
-}
\ No newline at end of file
+    min > min_period && max < max_period && (max - min) < allowed_deviation
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bps_off_time_fills_the_rest_of_the_period() {
+        assert_eq!(bps_off_time_us(10, 400), 99_600);
+        assert_eq!(bps_off_time_us(1, 0), 1_000_000);
+        // an on time longer than the period leaves no off time, and zero bps is treated as one
+        assert_eq!(bps_off_time_us(10_000, 400), 0);
+        assert_eq!(bps_off_time_us(0, 400), 999_600);
+    }
+
+    #[test]
+    fn duty_limit_lengthens_short_off_times() {
+        // 400us at 5% needs at least 7600us off
+        assert_eq!(duty_limited_off_time_us(400, 100_000, 5), 100_000);
+        assert_eq!(duty_limited_off_time_us(400, 1_000, 5), 7_600);
+        // rounds the off time up rather than letting the duty creep over the limit
+        assert_eq!(duty_limited_off_time_us(401, 0, 3), 12_966);
+        assert_eq!(duty_limited_off_time_us(400, 0, 100), 0);
+        assert_eq!(duty_limited_off_time_us(400, 0, 0), 39_600);
+    }
+
+    #[test]
+    fn soft_start_ramps_from_the_initial_fraction() {
+        assert_eq!(soft_start_angle(0.5, 0, 100), 0.5 * SOFT_START_INITIAL_FRACTION);
+        assert!((soft_start_angle(1.0, 50, 100) - (SOFT_START_INITIAL_FRACTION + 1.0) / 2.0).abs() < 1e-6);
+        assert_eq!(soft_start_angle(0.5, 100, 100), 0.5);
+        assert_eq!(soft_start_angle(0.5, 1_000, 100), 0.5);
+        assert_eq!(soft_start_angle(0.5, 0, 0), 0.5);
+    }
+
+    #[test]
+    fn derating_falls_from_start_to_limit() {
+        assert_eq!(derating_factor(20.0, 65.0, 80.0), 1.0);
+        assert_eq!(derating_factor(65.0, 65.0, 80.0), 1.0);
+        assert!((derating_factor(72.5, 65.0, 80.0) - 0.5).abs() < 1e-6);
+        assert_eq!(derating_factor(80.0, 65.0, 80.0), 0.0);
+        assert_eq!(derating_factor(100.0, 65.0, 80.0), 0.0);
+        assert_eq!(derating_factor(70.0, 65.0, 65.0), 0.0);
+    }
+
+    #[test]
+    fn tail_ramp_tapers_the_end_of_the_burst() {
+        assert_eq!(tail_ramp_factor(0, 400, 20), 1.0);
+        assert_eq!(tail_ramp_factor(380, 400, 20), 1.0);
+        assert_eq!(tail_ramp_factor(390, 400, 20), 0.5);
+        assert_eq!(tail_ramp_factor(400, 400, 20), 0.0);
+        assert_eq!(tail_ramp_factor(500, 400, 20), 0.0);
+        assert_eq!(tail_ramp_factor(399, 400, 0), 1.0);
+    }
+
+    #[test]
+    fn feedback_variance_checks_bounds_and_spread() {
+        assert!(feedback_variance_acceptable(10, 600, 700, &[650, 655, 659]));
+        assert!(!feedback_variance_acceptable(10, 600, 700, &[650, 655, 660]));
+        // the bounds are exclusive
+        assert!(!feedback_variance_acceptable(10, 650, 700, &[650, 655, 651]));
+        assert!(!feedback_variance_acceptable(10, 600, 655, &[650, 655, 651]));
+        // an empty history slot never passes
+        assert!(!feedback_variance_acceptable(10, 0, u16::MAX, &[650, 655, 0]));
+    }
+
+    #[test]
+    fn sweep_starts_at_the_center_and_steps_outward() {
+        assert_eq!(sweep_period(666, 60, 1, 0), 666);
+        let steps: [u16; 3] = [0, 1, 2];
+        assert_eq!(steps.map(|step| sweep_period(666, 60, 3, step)), [666, 726, 606]);
+        let steps: [u16; 5] = [0, 1, 2, 3, 4];
+        assert_eq!(steps.map(|step| sweep_period(666, 60, 5, step)), [666, 696, 636, 726, 606]);
+        assert_eq!(sweep_period(666, 60, 2, 1), 726);
+    }
+
+    #[test]
+    fn sweep_only_settles_on_the_drive_period() {
+        assert!(sweep_settled(666, 10, &[664, 668, 670]));
+        // steady, but not at the drive period, so it's still off resonance
+        assert!(!sweep_settled(606, 10, &[664, 668, 670]));
+        // at the drive period, but not steady yet
+        assert!(!sweep_settled(666, 10, &[657, 667, 675]));
+        assert!(!sweep_settled(666, 10, &[666, 666, 0]));
+    }
+
+    #[test]
+    fn lock_window_is_centered_on_the_startup_period() {
+        // the defaults lock anything longer than the startup period, and nothing shorter
+        let (min, max) = lock_window(STARTUP_PERIOD, LOCK_RANGE_BELOW_KHZ, LOCK_RANGE_ABOVE_KHZ, 0.0);
+        assert_eq!((min, max), (STARTUP_PERIOD, u16::MAX));
+        assert!(!feedback_variance_acceptable(PERIOD_OFFSET_MAX, min, max, &[606, 606, 606]));
+        assert!(feedback_variance_acceptable(PERIOD_OFFSET_MAX, min, max, &[700, 700, 700]));
+        // 400 kHz is 1000 clocks, and +/- 100 kHz either side is 800 to 1333 clocks
+        assert_eq!(lock_window(1000, 100.0, 100.0, 0.0), (800, 1333));
+        assert_eq!(lock_window(1000, 50.0, 50.0, 50.0), (800, 1333));
+    }
+}
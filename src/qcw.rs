@@ -1,9 +1,13 @@
 #![allow(unused)]
 
+use core::cell::{Cell, RefCell};
+
 use cortex_m::delay;
+use cortex_m::interrupt::Mutex;
 use stm32h7::stm32h753::Peripherals;
 
 use crate::device_access::with_devices_mut;
+use crate::time::micros_with_devices;
 
 /*
 QCW Signal Path
@@ -59,6 +63,17 @@ turn-off like we usually want for QCW ramps.
 
 It also protects us in the event that feedback stops working for some other reason.
 
+The overcurrent detect signal from the current sense comparator is wired into HRTIM fault input 1
+(GPIO A15), and both output timers are set to force their outputs into the idle state on a fault.
+This turns the bridge off in hardware with no software latency, and the outputs stay that way until
+reset, since the main loop latches an overcurrent trip rather than ever clearing the fault.
+
+With a conduction angle below 180 degrees, the leg that switches second (the lagging leg) turns on
+while current is still flowing the wrong way and hard-switches, while the leading leg gets to soft
+switch. To share that stress between both legs, closed loop operation can flip the phases, swapping
+the Timer B compare values so that Timer C leads and Timer A lags. Flipping every few cycles means
+each bridge leg only hard-switches half the time.
+
                                                                   [Trigger B] x    x [Trigger A]
                                                                               |    |
                                                                               |    |
@@ -88,11 +103,16 @@ It also protects us in the event that feedback stops working for some other reas
 
 pub fn init() {
     with_devices_mut(|devices, _| {
-        // Setup the output timers first, so we enable gpio in to a known-good state. Initially, pull-downs
+        // Setup the fault input before the output timers, so that the outputs are protected as soon as
+        // they're enabled.
+        setup_fault_input(devices, OVERCURRENT_FAULT_POLARITY);
+        // Setup the output timers next, so we enable gpio in to a known-good state. Initially, pull-downs
         // on the gate driver inputs should prevent us from activating the bridge at all.
         setup_output_timers(devices);
+        // the on-time backstop forces the outputs idle through burst mode, so it goes in right after them
+        setup_on_time_backstop(devices);
         // setup the input capture timer
-        setup_capture_timer(devices);
+        setup_capture_timer(devices, FEEDBACK_EDGE);
         // Setup the phase timer (timer b) generally.
         setup_phase_timer(devices);
         // setup the signal path as disabled initially
@@ -169,6 +189,140 @@ fn setup_gpio(devices: &mut Peripherals) {
 }
 
 const HRTIM_PRESCALER_1: u8 = 0b101;
+const HRTIM_PRESCALER_4: u8 = 0b111;
+
+// fault state of an output: forced to its inactive or active level
+const HRTIM_FAULT_ACTIVE: u8 = 0b01;
+const HRTIM_FAULT_INACTIVE: u8 = 0b10;
+
+/*
+    Idle levels of each bridge leg's complementary output pair, which the outputs also go to on a fault or
+    while the on-time backstop holds them. Which level turns which transistor on depends on the gate
+    drivers, so this is selectable per leg, but the two legs have to idle in the same state: that way both
+    ends of the primary are tied to the same rail, and no current can flow through it. A mismatch fails the
+    build rather than risk driving DC into the primary.
+*/
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum IdlePolarity {
+    // output 1 idles low and output 2 high
+    Output1Low,
+    // output 1 idles high and output 2 low
+    Output1High,
+}
+
+pub const LEG_A_IDLE: IdlePolarity = IdlePolarity::Output1Low;
+pub const LEG_C_IDLE: IdlePolarity = IdlePolarity::Output1Low;
+
+const _: () = assert!(
+    matches!((LEG_A_IDLE, LEG_C_IDLE), (IdlePolarity::Output1Low, IdlePolarity::Output1Low) | (IdlePolarity::Output1High, IdlePolarity::Output1High)),
+    "both bridge legs must idle in the same state"
+);
+
+// the (output 1, output 2) idle levels for a leg, true being high
+fn idle_levels(polarity: IdlePolarity) -> (bool, bool) {
+    match polarity {
+        IdlePolarity::Output1Low => (false, true),
+        IdlePolarity::Output1High => (true, false),
+    }
+}
+
+// the fault state that holds an output at the given idle level
+fn fault_state(idle_high: bool) -> u8 {
+    if idle_high {
+        HRTIM_FAULT_ACTIVE
+    } else {
+        HRTIM_FAULT_INACTIVE
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum FaultPolarity {
+    ActiveHigh,
+    ActiveLow,
+}
+
+// the current sense comparator pulls the overcurrent line high on a trip
+pub const OVERCURRENT_FAULT_POLARITY: FaultPolarity = FaultPolarity::ActiveHigh;
+
+fn setup_fault_input(devices: &mut Peripherals, polarity: FaultPolarity) {
+    /*
+        setup GPIO A15 to be the HRTIM FLT1 input, pulled to the inactive level so
+        a disconnected comparator doesn't hold the bridge in fault
+        */
+    devices.GPIOA.afrh.modify(|_, w| {
+        w.afr15().af2()
+    });
+    devices.GPIOA.pupdr.modify(|_, w| {
+        match polarity {
+            FaultPolarity::ActiveHigh => w.pupdr15().pull_down(),
+            FaultPolarity::ActiveLow => w.pupdr15().pull_up(),
+        }
+    });
+    devices.GPIOA.moder.modify(|_, w| {
+        w.moder15().alternate()
+    });
+    // fault 1 sourced from its input pin, with a small filter to reject switching noise
+    devices.HRTIM_COMMON.fltinr1.modify(|_, w| {
+        w
+            .flt1src().clear_bit()
+            .flt1p().bit(polarity == FaultPolarity::ActiveHigh)
+            .flt1f().variant(0b0010)
+    });
+    devices.HRTIM_COMMON.icr.write(|w| w.flt1c().set_bit());
+    devices.HRTIM_COMMON.fltinr1.modify(|_, w| {
+        w.flt1e().set_bit()
+    });
+}
+
+// true if the overcurrent fault has tripped and forced the outputs off
+pub fn overcurrent_fault(devices: &mut Peripherals) -> bool {
+    devices.HRTIM_COMMON.isr.read().flt1().bit_is_set()
+}
+
+// when set, the bridge outputs are held disabled no matter what, for dry running the control logic
+static OUTPUTS_INHIBITED: Mutex<Cell<bool>> = Mutex::new(Cell::new(false));
+
+/*
+    Inhibits the bridge outputs for bench testing. Everything else (timers, capture, lock detection)
+    keeps running as normal, so lock behavior can be checked against a signal generator on the feedback
+    input without any gate drive reaching the bridge.
+*/
+pub fn set_output_inhibit(devices: &mut Peripherals, inhibited: bool) {
+    cortex_m::interrupt::free(|cs| OUTPUTS_INHIBITED.borrow(cs).set(inhibited));
+    set_outputs_enabled(devices, !inhibited);
+}
+
+pub fn outputs_inhibited() -> bool {
+    cortex_m::interrupt::free(|cs| OUTPUTS_INHIBITED.borrow(cs).get())
+}
+
+/*
+    Enables or disables all four bridge outputs, independently of the signal path. Disabled outputs sit
+    at their idle levels no matter what the timers are doing, so this works as a safety gate even in the
+    middle of a burst. Before enabling, the signal path is disabled so that the output timers aren't
+    being triggered, and the outputs come back in their idle state rather than mid-cycle. While outputs
+    are inhibited, enabling them does nothing.
+*/
+pub fn set_outputs_enabled(devices: &mut Peripherals, enabled: bool) {
+    if enabled && !outputs_inhibited() {
+        configure_signal_path(devices, SignalPathConfig::Disabled);
+        devices.HRTIM_COMMON.oenr.write(|w| {
+            w
+                .ta1oen().set_bit()
+                .ta2oen().set_bit()
+                .tc1oen().set_bit()
+                .tc2oen().set_bit()
+        });
+    } else {
+        devices.HRTIM_COMMON.disr.write(|w| {
+            w
+                .ta1odis().set_bit()
+                .ta2odis().set_bit()
+                .tc1odis().set_bit()
+                .tc2odis().set_bit()
+        });
+    }
+}
 
 fn setup_output_timers(devices: &mut Peripherals) {
     devices.HRTIM_TIMA.timacr.modify(|_, w| {
@@ -201,16 +355,21 @@ fn setup_output_timers(devices: &mut Peripherals) {
     devices.HRTIM_TIMA.rstar.modify(|_, w| {
         w.timbcmp1().set_bit() // reset the timer on timer b cmp1
     });
-    // set the idle state of timer a outputs to be low/high on A and !A outputs respectively
+    // set the idle and fault states of timer a's A and !A outputs
+    let (idle1, idle2) = idle_levels(LEG_A_IDLE);
     devices.HRTIM_TIMA.outar.modify(|_, w| {
         w
-            .idles1().clear_bit()
-            .idles2().set_bit()
+            .idles1().bit(idle1)
+            .idles2().bit(idle2)
             .dten().set_bit()
             .pol1().clear_bit()
             .pol2().clear_bit()
-            .fault1().variant(0b00)
-            .fault2().variant(0b00)
+            .fault1().variant(fault_state(idle1))
+            .fault2().variant(fault_state(idle2))
+    });
+    // force the outputs to their idle states on an overcurrent fault
+    devices.HRTIM_TIMA.fltar.modify(|_, w| {
+        w.flt1en().set_bit()
     });
     devices.HRTIM_TIMA.perar.modify(|_, w| {
         w.perx().variant(0xF000) // set period to something long enough that it won't occur while running
@@ -247,16 +406,20 @@ fn setup_output_timers(devices: &mut Peripherals) {
             .dtprsc().variant(0b011)
     });
 
-    // set the idle state of timer c outputs to be low/high on B and !B outputs respectively
+    // set the idle and fault states of timer c's B and !B outputs
+    let (idle1, idle2) = idle_levels(LEG_C_IDLE);
     devices.HRTIM_TIMC.outcr.modify(|_, w| {
         w
-            .idles1().clear_bit()
-            .idles2().set_bit()
+            .idles1().bit(idle1)
+            .idles2().bit(idle2)
             .dten().set_bit()
             .pol1().clear_bit()
             .pol2().clear_bit()
-            .fault1().variant(0b00)
-            .fault2().variant(0b00)
+            .fault1().variant(fault_state(idle1))
+            .fault2().variant(fault_state(idle2))
+    });
+    devices.HRTIM_TIMC.fltcr.modify(|_, w| {
+        w.flt1en().set_bit()
     });
     devices.HRTIM_TIMC.percr.modify(|_, w| {
         w.perx().variant(0xF000) // set period to something long enough that it won't occur while running
@@ -287,6 +450,119 @@ fn setup_output_timers(devices: &mut Peripherals) {
     });
 }
 
+/*
+    On-Time Backstop
+    ----------------
+    The master timer isn't otherwise used, so it times a hardware limit on how long a burst can run. While
+    armed, it counts in continuous mode at 100 MHz, and its repetition counter stretches that out to the
+    limit. The repetition event triggers the burst mode controller, which forces all four bridge outputs
+    to their idle levels without any help from software. The burst mode idle time is as long as the
+    controller can count (about 5 seconds), and the master keeps retriggering it while it runs, so the
+    outputs stay idle until software disarms the backstop, or the watchdog resets us.
+
+    Software disarms the backstop at the end of every burst, so in normal running it never fires.
+*/
+// no burst is ever allowed to run longer than this, whatever limit the backstop is armed with. It's a fixed
+// safety limit rather than a setting, and sits well above any burst length this driver is meant for.
+pub const ABSOLUTE_MAX_ON_TIME_US: u32 = 50_000;
+
+const BACKSTOP_CLOCKS_PER_US: u32 = 100;
+const BACKSTOP_MAX_PERIOD: u32 = 0xFFDF;
+const BACKSTOP_MAX_REPETITIONS: u32 = 256;
+// burst mode clocked from fHRTIM / 32768
+const BURST_MODE_CLOCK_PRESCALED: u8 = 0b1010;
+const BURST_MODE_PRESCALER_32768: u8 = 0b1111;
+
+fn setup_on_time_backstop(devices: &mut Peripherals) {
+    devices.HRTIM_MASTER.mcr.modify(|_, w| {
+        w
+            .ck_psc().variant(HRTIM_PRESCALER_4)
+            .cont().set_bit()
+    });
+    // both outputs of both legs go to their idle levels while burst mode is idling them
+    devices.HRTIM_TIMA.outar.modify(|_, w| {
+        w
+            .idlem1().set_bit()
+            .idlem2().set_bit()
+    });
+    devices.HRTIM_TIMC.outcr.modify(|_, w| {
+        w
+            .idlem1().set_bit()
+            .idlem2().set_bit()
+    });
+    // single shot bursts, each idling the outputs for the whole burst period
+    devices.HRTIM_COMMON.bmcr.modify(|_, w| {
+        w
+            .bmclk().variant(BURST_MODE_CLOCK_PRESCALED)
+            .bmprsc().variant(BURST_MODE_PRESCALER_32768)
+            .bmom().clear_bit()
+    });
+    devices.HRTIM_COMMON.bmcmpr6.modify(|_, w| w.bmcmp().variant(0xFFFF));
+    devices.HRTIM_COMMON.bmper.modify(|_, w| w.bmper().variant(0xFFFF));
+    devices.HRTIM_COMMON.bmtrg.modify(|_, w| {
+        w.mstrep().set_bit()
+    });
+}
+
+/*
+    Arms the backstop to force the outputs idle if they're still running max_on_time_us from now. The limit
+    rounds up to a whole number of master periods, and is capped at ABSOLUTE_MAX_ON_TIME_US. Arming also
+    ends any burst mode idle left over from a previous trip.
+*/
+pub fn arm_on_time_backstop(devices: &mut Peripherals, max_on_time_us: u32) {
+    let max_on_time_us = max_on_time_us.min(ABSOLUTE_MAX_ON_TIME_US);
+    let total_clocks = max_on_time_us.saturating_mul(BACKSTOP_CLOCKS_PER_US).max(1);
+    let repetitions = total_clocks.div_ceil(BACKSTOP_MAX_PERIOD).clamp(1, BACKSTOP_MAX_REPETITIONS);
+    let period = total_clocks.div_ceil(repetitions).min(BACKSTOP_MAX_PERIOD);
+
+    devices.HRTIM_MASTER.mcr.modify(|_, w| w.mcen().clear_bit());
+    devices.HRTIM_COMMON.bmcr.modify(|_, w| w.bme().clear_bit());
+    devices.HRTIM_MASTER.mper.modify(|_, w| w.mper().variant(period as u16));
+    devices.HRTIM_MASTER.mrep.modify(|_, w| w.mrep().variant((repetitions - 1) as u8));
+    // reset and load the master while it's stopped, since a reset once running would count as a repetition
+    devices.HRTIM_COMMON.cr2.modify(|_, w| {
+        w
+            .mrst().set_bit()
+            .mswu().set_bit()
+    });
+    devices.HRTIM_COMMON.bmcr.modify(|_, w| w.bme().set_bit());
+    devices.HRTIM_MASTER.mcr.modify(|_, w| w.mcen().set_bit());
+}
+
+pub fn disarm_on_time_backstop(devices: &mut Peripherals) {
+    devices.HRTIM_MASTER.mcr.modify(|_, w| w.mcen().clear_bit());
+}
+
+// true if the backstop has fired and burst mode is holding the outputs idle
+pub fn on_time_backstop_tripped(devices: &mut Peripherals) -> bool {
+    devices.HRTIM_COMMON.bmcr.read().bmstat().bit_is_set()
+}
+
+/*
+    Cycle Counting
+    --------------
+    Timer B is reset once per switching cycle, by its own period in open loop and by the feedback in closed
+    loop, so its repetition counter counts switching cycles in hardware. The HRTIM doesn't let us read the
+    count itself back, only flags when it runs out, so this gives an event every n cycles rather than a
+    running count. The new interval is preloaded and takes effect from the next update.
+*/
+pub fn set_cycle_event_interval(devices: &mut Peripherals, cycles: u16) {
+    devices.HRTIM_TIMB.repbr.modify(|_, w| {
+        w.repx().variant((cycles.clamp(1, 256) - 1) as u8)
+    });
+    devices.HRTIM_TIMB.timbicr.write(|w| w.repc().set_bit());
+}
+
+// true (once) if another interval of switching cycles has passed since the last call
+pub fn take_cycle_event(devices: &mut Peripherals) -> bool {
+    if devices.HRTIM_TIMB.timbisr.read().rep().bit_is_set() {
+        devices.HRTIM_TIMB.timbicr.write(|w| w.repc().set_bit());
+        true
+    } else {
+        false
+    }
+}
+
 fn setup_phase_timer(devices: &mut Peripherals) {
     // There's not much setup to do initially, since it's mostly handled in signal path configuration
     devices.HRTIM_TIMB.timbcr.modify(|_, w| {
@@ -302,13 +578,35 @@ fn setup_phase_timer(devices: &mut Peripherals) {
     });
 }
 
-fn setup_capture_timer(devices: &mut Peripherals) {
-    // set external event 3 to be gpio D5, rising edge sensetive
+// which edge of the feedback signal marks the start of a cycle
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum FeedbackEdge {
+    Rising,
+    Falling,
+}
+
+// the feedback front-end is non-inverting, so the rising edge is where the primary current crosses zero
+pub const FEEDBACK_EDGE: FeedbackEdge = FeedbackEdge::Rising;
+
+// external event sensitivity values for eecr1
+const HRTIM_EEV_RISING_EDGE: u8 = 0b01;
+const HRTIM_EEV_FALLING_EDGE: u8 = 0b10;
+
+fn set_feedback_sensitivity(devices: &mut Peripherals, edge: FeedbackEdge) {
     devices.HRTIM_COMMON.eecr1.modify(|_, w| {
-        w
-            .ee3src().variant(0)
-            .ee3sns().variant(1)
+        w.ee3sns().variant(match edge {
+            FeedbackEdge::Rising => HRTIM_EEV_RISING_EDGE,
+            FeedbackEdge::Falling => HRTIM_EEV_FALLING_EDGE,
+        })
     });
+}
+
+fn setup_capture_timer(devices: &mut Peripherals, edge: FeedbackEdge) {
+    // set external event 3 to be gpio D5, sensitive to the feedback edge we sync to
+    devices.HRTIM_COMMON.eecr1.modify(|_, w| {
+        w.ee3src().variant(0)
+    });
+    set_feedback_sensitivity(devices, edge);
     // setup the capture timer to measure the period of pulses on the EEV3 input
     devices.HRTIM_TIMD.timdcr.modify(|_, w| {
         w.ck_pscx().variant(HRTIM_PRESCALER_1)
@@ -331,24 +629,370 @@ fn setup_capture_timer(devices: &mut Peripherals) {
     devices.HRTIM_MASTER.mcr.modify(|_, w| w.tdcen().set_bit());
 }
 
+// time::micros() of the last fresh feedback capture, so we can tell a dead feedback input from a wrong frequency
+static LAST_CAPTURE_US: Mutex<Cell<u64>> = Mutex::new(Cell::new(0));
+
+/*
+    The shortest period we'll ever drive the bridge at, or accept as a feedback capture: 200 clocks is 2 MHz,
+    well above any coil this is meant for. Anything shorter is a glitch or a bad setting, and would give
+    degenerate compare timings, so it's rejected and counted rather than acted on.
+*/
+pub const MIN_PERIOD_CLOCKS: u16 = 200;
+
+// total periods rejected for being under MIN_PERIOD_CLOCKS since boot, from either captures or signal path configs
+static SHORT_PERIOD_REJECTIONS: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+
+fn count_short_period() {
+    cortex_m::interrupt::free(|cs| {
+        let rejections = SHORT_PERIOD_REJECTIONS.borrow(cs);
+        rejections.set(rejections.get().wrapping_add(1));
+    });
+}
+
+pub fn short_period_rejections() -> u32 {
+    cortex_m::interrupt::free(|cs| SHORT_PERIOD_REJECTIONS.borrow(cs).get())
+}
+
 pub fn read_capture_timer(devices: &mut Peripherals) -> Option<u16> {
     if devices.HRTIM_TIMD.timdisr.read().cpt1().bit_is_set() {
         let value = devices.HRTIM_TIMD.cpt1dr.read().cpt1x().bits();
         devices.HRTIM_TIMD.timdicr.write(|w| w.cpt1c().set_bit());
+        // an edge still means the feedback is alive, even if the period it gives is too short to use
+        let now = micros_with_devices(devices);
+        cortex_m::interrupt::free(|cs| LAST_CAPTURE_US.borrow(cs).set(now));
+        if value < MIN_PERIOD_CLOCKS {
+            count_short_period();
+            return None;
+        }
         Some(value)
     } else {
         None
     }
 }
 
+pub fn micros_since_last_capture(devices: &Peripherals) -> u64 {
+    let last = cortex_m::interrupt::free(|cs| LAST_CAPTURE_US.borrow(cs).get());
+    micros_with_devices(devices).saturating_sub(last)
+}
+
+// true if the feedback input has produced a capture within the last timeout_us
+pub fn feedback_alive(devices: &Peripherals, timeout_us: u64) -> bool {
+    micros_since_last_capture(devices) < timeout_us
+}
+
+// the most captures we keep around for median filtering the feedback period
+pub const CAPTURE_HISTORY_LENGTH: usize = 8;
+
+// this many outliers in a row means the period really has moved, so the next one is taken anyway
+const MAX_CONSECUTIVE_REJECTIONS: u32 = 3;
+
+struct CaptureHistory {
+    periods: [u16; CAPTURE_HISTORY_LENGTH],
+    next: usize,
+    count: usize,
+    consecutive_rejections: u32,
+}
+
+impl CaptureHistory {
+    // median of the last n captures (up to however many we have), or None if there aren't any yet
+    fn median(&self, n: usize) -> Option<u16> {
+        if self.count == 0 {
+            return None;
+        }
+        let n = n.clamp(1, self.count);
+        let mut window = [0u16; CAPTURE_HISTORY_LENGTH];
+        for (i, slot) in window.iter_mut().take(n).enumerate() {
+            *slot = self.periods[(self.next + CAPTURE_HISTORY_LENGTH - 1 - i) % CAPTURE_HISTORY_LENGTH];
+        }
+        let window = &mut window[..n];
+        window.sort_unstable();
+        Some(window[n / 2])
+    }
+
+    fn push(&mut self, value: u16) {
+        self.periods[self.next] = value;
+        self.next = (self.next + 1) % CAPTURE_HISTORY_LENGTH;
+        self.count = (self.count + 1).min(CAPTURE_HISTORY_LENGTH);
+    }
+}
+
+static CAPTURE_HISTORY: Mutex<RefCell<CaptureHistory>> = Mutex::new(RefCell::new(CaptureHistory {
+    periods: [0; CAPTURE_HISTORY_LENGTH],
+    next: 0,
+    count: 0,
+    consecutive_rejections: 0,
+}));
+
+// total captures thrown away as outliers since boot, as a diagnostic for a noisy feedback input
+static CAPTURE_REJECTIONS: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+
+// forget all the captures the filter has seen, so a new burst doesn't start with stale periods
+pub fn reset_capture_filter() {
+    cortex_m::interrupt::free(|cs| {
+        let mut history = CAPTURE_HISTORY.borrow(cs).borrow_mut();
+        history.next = 0;
+        history.count = 0;
+        history.consecutive_rejections = 0;
+    });
+}
+
+pub fn capture_rejections() -> u32 {
+    cortex_m::interrupt::free(|cs| CAPTURE_REJECTIONS.borrow(cs).get())
+}
+
+// a spread of captured periods this wide, relative to their median, scores zero lock quality
+const LOCK_QUALITY_ZERO_SPREAD_PERCENT: u32 = 10;
+
+/*
+    A 0-100 score of how cleanly the coil is oscillating, from the captures in the filter history. The
+    spread of the captured periods against their median sets the score, so a steady period scores high and
+    a jittery one low. It's scaled down while the history is still filling, since only a few captures
+    don't say much about how well the feedback holds up.
+*/
+pub fn lock_quality() -> u8 {
+    cortex_m::interrupt::free(|cs| {
+        let history = CAPTURE_HISTORY.borrow(cs).borrow();
+        let median = match history.median(CAPTURE_HISTORY_LENGTH) {
+            Some(median) => median.max(1) as u32,
+            None => return 0,
+        };
+        let periods = &history.periods[..history.count];
+        let min = *periods.iter().min().unwrap_or(&0) as u32;
+        let max = *periods.iter().max().unwrap_or(&0) as u32;
+        let zero_spread = (median * LOCK_QUALITY_ZERO_SPREAD_PERCENT / 100).max(1);
+        let consistency = 100u32.saturating_sub((max - min) * 100 / zero_spread);
+        (consistency * history.count as u32 / CAPTURE_HISTORY_LENGTH as u32) as u8
+    })
+}
+
+/*
+    Reads the capture timer like read_capture_timer, but returns the median of the last n captured
+    periods (up to CAPTURE_HISTORY_LENGTH) rather than the raw value, so that a single noisy edge on
+    the feedback input can't produce a bogus period. Until n captures have been seen, the median of
+    the ones we do have is returned.
+
+    Once the history holds n captures, a capture more than max_deviation_percent away from the running
+    median is counted as a rejection and dropped, returning None as if nothing had been captured. A
+    max_deviation_percent of 0 disables the rejection.
+*/
+pub fn read_capture_timer_filtered(devices: &mut Peripherals, n: usize, max_deviation_percent: u16) -> Option<u16> {
+    let value = read_capture_timer(devices)?;
+    cortex_m::interrupt::free(|cs| {
+        let mut history = CAPTURE_HISTORY.borrow(cs).borrow_mut();
+        if max_deviation_percent != 0 && history.count >= n && history.consecutive_rejections < MAX_CONSECUTIVE_REJECTIONS {
+            if let Some(median) = history.median(n) {
+                let deviation = (value as i32 - median as i32).unsigned_abs();
+                if deviation * 100 > median as u32 * max_deviation_percent as u32 {
+                    history.consecutive_rejections += 1;
+                    let rejections = CAPTURE_REJECTIONS.borrow(cs);
+                    rejections.set(rejections.get().wrapping_add(1));
+                    return None;
+                }
+            }
+        }
+        history.consecutive_rejections = 0;
+        history.push(value);
+        history.median(n)
+    })
+}
+
+/*
+    How the closed loop conduction angle (and so the power) changes over a burst. Times are in
+    time::micros() microseconds, and every profile holds its starting value before t_start.
+
+    - A ramp goes linearly from start to end, and holds at end after t_start + t_ramp.
+    - An exponential approaches end from start with time constant tau.
+    - A table linearly interpolates between (microseconds after t_start, power) points, which must be in
+      increasing time order, and holds the first and last values outside of them.
+*/
+#[derive(Copy, Clone, Debug)]
+pub enum ClosedLoopPowerProfile {
+    Constant(f32),
+    Ramp { start: f32, end: f32, t_start: u64, t_ramp: u64 },
+    Exponential { start: f32, end: f32, t_start: u64, tau: u64 },
+    Table { points: &'static [(u32, f32)], t_start: u64 },
+}
+
+pub fn power_at(profile: &ClosedLoopPowerProfile, t_us: u64) -> f32 {
+    match *profile {
+        ClosedLoopPowerProfile::Constant(power) => power,
+        ClosedLoopPowerProfile::Ramp { start, end, t_start, t_ramp } => {
+            if t_us <= t_start {
+                start
+            } else if t_us - t_start >= t_ramp {
+                end
+            } else {
+                start + (end - start) * ((t_us - t_start) as f32 / t_ramp as f32)
+            }
+        },
+        ClosedLoopPowerProfile::Exponential { start, end, t_start, tau } => {
+            if t_us <= t_start {
+                start
+            } else if tau == 0 {
+                end
+            } else {
+                end + (start - end) * libm::expf(-((t_us - t_start) as f32 / tau as f32))
+            }
+        },
+        ClosedLoopPowerProfile::Table { points, t_start } => table_power_at(points, t_us.saturating_sub(t_start)),
+    }
+}
+
+fn table_power_at(points: &[(u32, f32)], t_us: u64) -> f32 {
+    let (first_t, first_power) = match points.first() {
+        Some(point) => *point,
+        None => return 0.0,
+    };
+    if t_us <= first_t as u64 {
+        return first_power;
+    }
+    for pair in points.windows(2) {
+        let (t0, power0) = pair[0];
+        let (t1, power1) = pair[1];
+        if t_us < t1 as u64 {
+            return power0 + (power1 - power0) * ((t_us - t0 as u64) as f32 / (t1 - t0) as f32);
+        }
+    }
+    points[points.len() - 1].1
+}
+
+/*
+    How the closed loop phase setpoint (the phase offset) changes over a burst, in degrees of the
+    feedback period. A sweep moves linearly from start to end, for characterizing the coil's response
+    to phase, and holds at the ends like a power ramp does.
+*/
+#[derive(Copy, Clone, Debug)]
+pub enum PhaseProfile {
+    Fixed(f32),
+    Sweep { start_deg: f32, end_deg: f32, t_start: u64, t_sweep: u64 },
+}
+
+pub fn phase_at(profile: &PhaseProfile, t_us: u64) -> f32 {
+    match *profile {
+        PhaseProfile::Fixed(degrees) => degrees,
+        PhaseProfile::Sweep { start_deg, end_deg, t_start, t_sweep } => {
+            power_at(&ClosedLoopPowerProfile::Ramp { start: start_deg, end: end_deg, t_start, t_ramp: t_sweep }, t_us)
+        }
+    }
+}
+
+/*
+    Converts a fraction of a period to HRTIM clocks, rounding to the nearest clock rather than
+    truncating, so timings don't all sit up to a clock early. At 400 MHz one clock is 2.5ns, which at
+    the 515 kHz operating point (a period of about 777 clocks) is a phase resolution of about 0.46
+    degrees.
+*/
+fn fraction_to_clocks(period_clocks: u16, fraction: f32) -> i32 {
+    libm::roundf(period_clocks as f32 * fraction) as i32
+}
+
+// HRTIM runs from the 400 MHz system clock
+pub const HRTIM_CLOCK_HZ: i64 = 400_000_000;
+pub const HRTIM_CLOCKS_PER_US: u64 = (HRTIM_CLOCK_HZ / 1_000_000) as u64;
+
+// the timer period in HRTIM clocks for a frequency in kHz, saturating at the longest period a timer can hold
+pub fn khz_to_period_clocks(khz: f32) -> u16 {
+    if khz <= 0.0 {
+        return u16::MAX;
+    }
+    libm::roundf(HRTIM_CLOCK_HZ as f32 / 1000.0 / khz).min(u16::MAX as f32) as u16
+}
+
+pub fn period_clocks_to_khz(period_clocks: u16) -> f32 {
+    HRTIM_CLOCK_HZ as f32 / 1000.0 / period_clocks.max(1) as f32
+}
+
+// converts a time in nanoseconds to the nearest whole number of HRTIM clocks
+pub fn ns_to_clocks(ns: i16) -> i16 {
+    let scaled = ns as i64 * HRTIM_CLOCK_HZ;
+    let half = if scaled < 0 { -500_000_000 } else { 500_000_000 };
+    ((scaled + half) / 1_000_000_000) as i16
+}
+
+// converts a signed phase in degrees to HRTIM clocks at the given period, for use as a phase offset
+pub fn degrees_to_clocks(period_clocks: u16, degrees: f32) -> i16 {
+    fraction_to_clocks(period_clocks, degrees / 360.0).clamp(i16::MIN as i32, i16::MAX as i32) as i16
+}
+
+// per-leg offsets in HRTIM clocks added to each leg's closed loop trigger, to even out asymmetric gate
+// drive propagation. Positive trims delay the leg.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct LegTrims {
+    pub a: i16,
+    pub c: i16,
+}
+
 #[derive(Copy, Clone, Debug)]
 pub enum SignalPathConfig {
     Disabled,
     OpenLoop { period_clocks: u16, conduction_angle: f32 },
-    ClosedLoop { period_clocks: u16, conduction_angle: f32, zero_angle: f32, delay_comp: u16 },
+    ClosedLoop { period_clocks: u16, conduction_angle: f32, zero_angle: f32, phase_offset: i16, flip_phases: bool, leg_trims: LegTrims },
+}
+
+fn open_loop_conduction_delay(period_clocks: u16, conduction_angle: f32) -> u16 {
+    let half_period = period_clocks / 2;
+    let quarter_period = half_period / 2;
+    quarter_period + fraction_to_clocks(half_period, conduction_angle).clamp(0, half_period as i32) as u16
+}
+
+/*
+    Changes the conduction angle of an already running open loop signal path, without resetting timer b
+    like configure_signal_path does. The new timing is preloaded, and takes effect at the end of the
+    current period, so this can be called every cycle to ramp the conduction angle smoothly.
+*/
+pub fn set_open_loop_conduction_angle(devices: &mut Peripherals, period_clocks: u16, conduction_angle: f32) {
+    if period_clocks < MIN_PERIOD_CLOCKS {
+        count_short_period();
+        return;
+    }
+    devices.HRTIM_TIMB.cmp2br.modify(|_, w| {
+        w.cmp2x().variant(open_loop_conduction_delay(period_clocks, conduction_angle))
+    });
+}
+
+// true if config would drive the bridge at a period under MIN_PERIOD_CLOCKS
+fn period_too_short(config: &SignalPathConfig) -> bool {
+    match *config {
+        SignalPathConfig::OpenLoop { period_clocks, .. } | SignalPathConfig::ClosedLoop { period_clocks, .. } => period_clocks < MIN_PERIOD_CLOCKS,
+        SignalPathConfig::Disabled => false,
+    }
 }
 
+/*
+    The timer b compare values (cmp1 triggering timer a, cmp2 timer c) for a closed loop config, as offsets
+    from the feedback zero crossing that retriggers timer b.
+
+    Timer a normally leads, and flipping swaps the legs so timer c leads instead. The trims follow the
+    legs through a flip, and the phase offset then moves both triggers the same way, positive later and
+    negative earlier, shifting the whole conduction window relative to the zero crossing. Propagation
+    delay compensation goes through the phase offset too, since it has to pull both triggers earlier by
+    the full delay. Both compare values are kept within the period.
+*/
+fn closed_loop_compares(period_clocks: u16, conduction_angle: f32, zero_angle: f32, phase_offset: i16, flip_phases: bool, leg_trims: LegTrims) -> (u16, u16) {
+    let period = period_clocks as i32;
+    let zero_delay = fraction_to_clocks(period_clocks, zero_angle);
+    let conduction_delay = fraction_to_clocks(period_clocks, zero_angle + conduction_angle);
+    let (cmp1, cmp2) = if flip_phases {
+        (conduction_delay, zero_delay)
+    } else {
+        (zero_delay, conduction_delay)
+    };
+    let cmp1 = (cmp1 + leg_trims.a as i32 + phase_offset as i32).clamp(0, period) as u16;
+    let cmp2 = (cmp2 + leg_trims.c as i32 + phase_offset as i32).clamp(0, period) as u16;
+    (cmp1, cmp2)
+}
+
+/*
+    Sets up the signal path for the given config. An open or closed loop config with a period under
+    MIN_PERIOD_CLOCKS is rejected, and the signal path is disabled instead.
+*/
 pub fn configure_signal_path(devices: &mut Peripherals, config: SignalPathConfig) {
+    let config = if period_too_short(&config) {
+        count_short_period();
+        SignalPathConfig::Disabled
+    } else {
+        config
+    };
     match config {
         SignalPathConfig::Disabled => {
             /* 
@@ -382,7 +1026,7 @@ pub fn configure_signal_path(devices: &mut Peripherals, config: SignalPathConfig
                 w.cmp1x().variant(quarter_period)
             });
             devices.HRTIM_TIMB.cmp2br.modify(|_, w| {
-                w.cmp2x().variant(quarter_period + (half_period as f32 * conduction_angle) as u16)
+                w.cmp2x().variant(open_loop_conduction_delay(period_clocks, conduction_angle))
             });
             devices.HRTIM_TIMB.perbr.modify(|_, w| {
                 w.perx().variant(period_clocks)
@@ -408,7 +1052,7 @@ pub fn configure_signal_path(devices: &mut Peripherals, config: SignalPathConfig
                 w.tbcen().set_bit()
             });
         },
-        SignalPathConfig::ClosedLoop { period_clocks, conduction_angle, zero_angle, delay_comp } => {
+        SignalPathConfig::ClosedLoop { period_clocks, conduction_angle, zero_angle, phase_offset, flip_phases, leg_trims } => {
             // disable updates to timer b while we modify it
             devices.HRTIM_COMMON.cr1.modify(|_, w| {
                 w.tbudis().set_bit()
@@ -432,10 +1076,9 @@ pub fn configure_signal_path(devices: &mut Peripherals, config: SignalPathConfig
             devices.HRTIM_TIMA.cmp1ar.modify(|_, w| w.cmp1x().variant(half_period));
             devices.HRTIM_TIMC.cmp1cr.modify(|_, w| w.cmp1x().variant(half_period));
 
-            let zero_delay = (period_clocks as f32 * zero_angle) as u16 - delay_comp;
-
-            devices.HRTIM_TIMB.cmp1br.modify(|_, w| w.cmp1x().variant(zero_delay));
-            devices.HRTIM_TIMB.cmp2br.modify(|_, w| w.cmp2x().variant(zero_delay + (period_clocks as f32 * conduction_angle) as u16));
+            let (cmp1, cmp2) = closed_loop_compares(period_clocks, conduction_angle, zero_angle, phase_offset, flip_phases, leg_trims);
+            devices.HRTIM_TIMB.cmp1br.modify(|_, w| w.cmp1x().variant(cmp1));
+            devices.HRTIM_TIMB.cmp2br.modify(|_, w| w.cmp2x().variant(cmp2));
 
             // re-enable updates to start doing them!
             devices.HRTIM_COMMON.cr1.modify(|_, w| {
@@ -445,3 +1088,155 @@ pub fn configure_signal_path(devices: &mut Peripherals, config: SignalPathConfig
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn periods_under_the_minimum_are_rejected() {
+        let open_loop = |period_clocks| SignalPathConfig::OpenLoop { period_clocks, conduction_angle: 0.3 };
+        let closed_loop = |period_clocks| SignalPathConfig::ClosedLoop {
+            period_clocks,
+            conduction_angle: 0.3,
+            zero_angle: 0.05,
+            phase_offset: 0,
+            flip_phases: false,
+            leg_trims: LegTrims::default(),
+        };
+        assert!(period_too_short(&open_loop(0)));
+        assert!(period_too_short(&open_loop(MIN_PERIOD_CLOCKS - 1)));
+        assert!(!period_too_short(&open_loop(MIN_PERIOD_CLOCKS)));
+        assert!(period_too_short(&closed_loop(0)));
+        assert!(period_too_short(&closed_loop(MIN_PERIOD_CLOCKS - 1)));
+        assert!(!period_too_short(&closed_loop(MIN_PERIOD_CLOCKS)));
+        assert!(!period_too_short(&SignalPathConfig::Disabled));
+    }
+
+    #[test]
+    fn closed_loop_compares_stay_within_the_period() {
+        let trims = LegTrims { a: 1000, c: -1000 };
+        let (cmp1, cmp2) = closed_loop_compares(MIN_PERIOD_CLOCKS, 0.9, 0.5, i16::MAX, false, trims);
+        assert!(cmp1 <= MIN_PERIOD_CLOCKS && cmp2 <= MIN_PERIOD_CLOCKS);
+        let (cmp1, cmp2) = closed_loop_compares(MIN_PERIOD_CLOCKS, 0.9, 0.5, i16::MIN, true, trims);
+        assert!(cmp1 <= MIN_PERIOD_CLOCKS && cmp2 <= MIN_PERIOD_CLOCKS);
+    }
+
+    #[test]
+    fn phase_offset_moves_both_compares_together() {
+        let (cmp1, cmp2) = closed_loop_compares(800, 0.3, 0.1, 0, false, LegTrims::default());
+        let (later1, later2) = closed_loop_compares(800, 0.3, 0.1, 20, false, LegTrims::default());
+        let (earlier1, earlier2) = closed_loop_compares(800, 0.3, 0.1, -20, false, LegTrims::default());
+        assert_eq!((later1, later2), (cmp1 + 20, cmp2 + 20));
+        assert_eq!((earlier1, earlier2), (cmp1 - 20, cmp2 - 20));
+    }
+
+    #[test]
+    fn fraction_to_clocks_rounds_to_nearest() {
+        assert_eq!(fraction_to_clocks(777, 0.1), 78);
+        assert_eq!(fraction_to_clocks(777, 0.3), 233);
+        assert_eq!(fraction_to_clocks(777, -0.1), -78);
+    }
+
+    #[test]
+    fn closed_loop_timings_are_monotonic_in_conduction_angle() {
+        let mut last_cmp2 = 0;
+        for step in 0..=100 {
+            let angle = step as f32 / 200.0;
+            let (cmp1, cmp2) = closed_loop_compares(777, angle, 0.05, 0, false, LegTrims::default());
+            assert_eq!(cmp1, fraction_to_clocks(777, 0.05) as u16);
+            assert!(cmp2 >= last_cmp2);
+            last_cmp2 = cmp2;
+        }
+        let mut last = 0;
+        for step in 0..=100 {
+            let delay = open_loop_conduction_delay(777, step as f32 / 100.0);
+            assert!(delay >= last);
+            last = delay;
+        }
+    }
+
+    #[test]
+    fn flipped_phases_are_mirror_images() {
+        for &(angle, phase_offset) in [(0.1, 0), (0.3, 10), (0.45, -40)].iter() {
+            let (cmp1, cmp2) = closed_loop_compares(777, angle, 0.05, phase_offset, false, LegTrims::default());
+            assert_eq!(closed_loop_compares(777, angle, 0.05, phase_offset, true, LegTrims::default()), (cmp2, cmp1));
+        }
+        // trims stay with their legs through a flip, so they're applied after the swap
+        let trims = LegTrims { a: 5, c: -3 };
+        let (cmp1, cmp2) = closed_loop_compares(777, 0.3, 0.05, 0, false, LegTrims::default());
+        assert_eq!(closed_loop_compares(777, 0.3, 0.05, 0, true, trims), (cmp2 + 5, cmp1 - 3));
+    }
+
+    #[test]
+    fn khz_and_period_clocks_convert_both_ways() {
+        assert_eq!(khz_to_period_clocks(400.0), 1000);
+        assert_eq!(khz_to_period_clocks(515.0), 777);
+        assert_eq!(khz_to_period_clocks(0.0), u16::MAX);
+        assert_eq!(khz_to_period_clocks(-10.0), u16::MAX);
+        assert_eq!(khz_to_period_clocks(1.0), u16::MAX);
+        assert_eq!(period_clocks_to_khz(1000), 400.0);
+        assert_eq!(khz_to_period_clocks(period_clocks_to_khz(666)), 666);
+        assert!(period_clocks_to_khz(0).is_finite());
+    }
+
+    #[test]
+    fn ns_to_clocks_rounds_to_nearest() {
+        assert_eq!(ns_to_clocks(0), 0);
+        assert_eq!(ns_to_clocks(10), 4);
+        assert_eq!(ns_to_clocks(-10), -4);
+        // 2.5ns per clock, so 6ns is 2.4 clocks and 7ns is 2.8
+        assert_eq!(ns_to_clocks(6), 2);
+        assert_eq!(ns_to_clocks(7), 3);
+        assert_eq!(ns_to_clocks(-7), -3);
+    }
+
+    #[test]
+    fn degrees_to_clocks_is_signed() {
+        assert_eq!(degrees_to_clocks(720, 90.0), 180);
+        assert_eq!(degrees_to_clocks(720, -90.0), -180);
+        assert_eq!(degrees_to_clocks(720, 0.0), 0);
+    }
+
+    #[test]
+    fn constant_power_never_changes() {
+        let profile = ClosedLoopPowerProfile::Constant(0.3);
+        assert_eq!(power_at(&profile, 0), 0.3);
+        assert_eq!(power_at(&profile, 1_000_000), 0.3);
+    }
+
+    #[test]
+    fn ramp_power_is_linear_and_holds_at_the_ends() {
+        let profile = ClosedLoopPowerProfile::Ramp { start: 0.1, end: 0.5, t_start: 100, t_ramp: 200 };
+        assert_eq!(power_at(&profile, 0), 0.1);
+        assert_eq!(power_at(&profile, 100), 0.1);
+        assert!((power_at(&profile, 200) - 0.3).abs() < 1e-6);
+        assert_eq!(power_at(&profile, 300), 0.5);
+        assert_eq!(power_at(&profile, 1_000), 0.5);
+    }
+
+    #[test]
+    fn exponential_power_approaches_the_end() {
+        let profile = ClosedLoopPowerProfile::Exponential { start: 0.1, end: 0.5, t_start: 100, tau: 50 };
+        assert_eq!(power_at(&profile, 0), 0.1);
+        assert_eq!(power_at(&profile, 100), 0.1);
+        // one time constant in covers 1 - 1/e of the way
+        assert!((power_at(&profile, 150) - (0.5 - 0.4 / core::f32::consts::E)).abs() < 1e-5);
+        assert!((power_at(&profile, 10_000) - 0.5).abs() < 1e-6);
+        let instant = ClosedLoopPowerProfile::Exponential { start: 0.1, end: 0.5, t_start: 100, tau: 0 };
+        assert_eq!(power_at(&instant, 101), 0.5);
+    }
+
+    #[test]
+    fn table_power_interpolates_between_points() {
+        static POINTS: [(u32, f32); 3] = [(10, 0.2), (20, 0.4), (40, 0.0)];
+        let profile = ClosedLoopPowerProfile::Table { points: &POINTS, t_start: 100 };
+        assert_eq!(power_at(&profile, 0), 0.2);
+        assert_eq!(power_at(&profile, 110), 0.2);
+        assert!((power_at(&profile, 115) - 0.3).abs() < 1e-6);
+        assert!((power_at(&profile, 130) - 0.2).abs() < 1e-6);
+        assert_eq!(power_at(&profile, 140), 0.0);
+        assert_eq!(power_at(&profile, 1_000), 0.0);
+        let empty = ClosedLoopPowerProfile::Table { points: &[], t_start: 100 };
+        assert_eq!(power_at(&empty, 150), 0.0);
+    }
+}
@@ -0,0 +1,63 @@
+#![allow(unused)]
+
+use core::cell::Cell;
+
+use cortex_m::interrupt::Mutex;
+use stm32h7::stm32h753::Peripherals;
+
+use crate::device_access::with_devices_mut;
+
+/*
+Why the MCU last reset, from the RCC reset flags. The flags are sticky across resets until cleared, so
+init reads them once at startup and clears them, so the next reset reports only its own cause.
+
+More than one flag is set for most resets (the NRST pin is driven on every reset, and a power-on also
+flags a brown-out), so the most specific cause wins.
+*/
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ResetCause {
+    PowerOn,
+    BrownOut,
+    Pin,
+    Software,
+    IndependentWatchdog,
+    WindowWatchdog,
+    LowPower,
+    Unknown,
+}
+
+static RESET_CAUSE: Mutex<Cell<ResetCause>> = Mutex::new(Cell::new(ResetCause::Unknown));
+
+pub fn init() {
+    with_devices_mut(|devices, _| {
+        init_with_devices(devices)
+    })
+}
+
+pub fn init_with_devices(devices: &mut Peripherals) {
+    let rsr = devices.RCC.rsr.read();
+    let cause = if rsr.lpwrrstf().bit_is_set() {
+        ResetCause::LowPower
+    } else if rsr.wwdg1rstf().bit_is_set() {
+        ResetCause::WindowWatchdog
+    } else if rsr.iwdg1rstf().bit_is_set() {
+        ResetCause::IndependentWatchdog
+    } else if rsr.sftrstf().bit_is_set() {
+        ResetCause::Software
+    } else if rsr.porrstf().bit_is_set() {
+        ResetCause::PowerOn
+    } else if rsr.borrstf().bit_is_set() {
+        ResetCause::BrownOut
+    } else if rsr.pinrstf().bit_is_set() {
+        ResetCause::Pin
+    } else {
+        ResetCause::Unknown
+    };
+    devices.RCC.rsr.modify(|_, w| w.rmvf().clear());
+    cortex_m::interrupt::free(|cs| RESET_CAUSE.borrow(cs).set(cause));
+}
+
+pub fn last() -> ResetCause {
+    cortex_m::interrupt::free(|cs| RESET_CAUSE.borrow(cs).get())
+}
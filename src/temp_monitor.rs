@@ -0,0 +1,183 @@
+#![allow(unused)]
+
+use stm32h7::stm32h753::{adc3, Peripherals};
+
+use crate::device_access::with_devices_mut;
+use crate::time::micros_with_devices;
+
+/*
+Bridge Temperature Monitor
+--------------------------
+
+An NTC thermistor on the bridge heatsink forms the bottom half of a divider from VDDA, with a fixed
+series resistor on top, and the midpoint goes to PA0 (ADC1 INP16). We convert the divider ratio
+back into a thermistor resistance, and then into a temperature with the Steinhart-Hart equation:
+
+    1 / T = A + B * ln(R) + C * ln(R)^3
+
+The coefficients and series resistance live in ThermistorConfig so different thermistors can be
+supported without touching the conversion.
+
+The control board's own die temperature comes from the internal temperature sensor on ADC3 (INP18),
+using the factory calibration values ST programs into system memory at 30 and 110 degrees C.
+*/
+
+#[derive(Copy, Clone, Debug)]
+pub struct ThermistorConfig {
+    pub series_resistance_ohms: f32,
+    pub a: f32,
+    pub b: f32,
+    pub c: f32,
+}
+
+// a common 10k, B=3950 NTC with a 10k series resistor
+pub const DEFAULT_THERMISTOR: ThermistorConfig = ThermistorConfig {
+    series_resistance_ohms: 10_000.0,
+    a: 1.009_249_5e-3,
+    b: 2.378_405_4e-4,
+    c: 2.019_202_7e-7,
+};
+
+const ADC_FULL_SCALE: f32 = 65535.0;
+// a 387.5 cycle sample plus conversion at 8 MHz takes about 50us, so this is plenty
+const CONVERSION_TIMEOUT_US: u64 = 100;
+const KELVIN_OFFSET: f32 = 273.15;
+
+// divider ratios this close to either rail are taken as a shorted or open thermistor rather than a
+// temperature. With the default thermistor that leaves roughly -55 to 160 degrees C readable.
+const THERMISTOR_RAIL_MARGIN: f32 = 0.02;
+
+const THERMISTOR_CHANNEL: u8 = 16;
+const TEMPERATURE_SENSOR_CHANNEL: u8 = 18;
+
+// factory calibration of the internal temperature sensor, 16 bit readings at 3.3V VDDA
+const TS_CAL1: *const u16 = 0x1FF1_E820 as *const u16;
+const TS_CAL2: *const u16 = 0x1FF1_E840 as *const u16;
+const TS_CAL1_TEMP_C: f32 = 30.0;
+const TS_CAL2_TEMP_C: f32 = 110.0;
+
+pub fn init() {
+    with_devices_mut(|devices, _| {
+        setup_gpio(devices);
+        // the kernel clock is per_ck (HSI, 64 MHz), divided by 4 to give the ADCs a 16 MHz clock
+        devices.ADC12_COMMON.ccr.modify(|_, w| {
+            w
+                .ckmode().asynchronous()
+                .presc().div4()
+        });
+        devices.ADC3_COMMON.ccr.modify(|_, w| {
+            w
+                .ckmode().asynchronous()
+                .presc().div4()
+                .vsenseen().enabled()
+        });
+        setup_adc(devices, &devices.ADC1, THERMISTOR_CHANNEL);
+        setup_adc(devices, &devices.ADC3, TEMPERATURE_SENSOR_CHANNEL);
+    })
+}
+
+fn setup_gpio(devices: &mut Peripherals) {
+    // PA0 is the thermistor divider input, in analog mode with no pulls
+    devices.GPIOA.moder.modify(|_, w| {
+        w.moder0().analog()
+    });
+    devices.GPIOA.pupdr.modify(|_, w| {
+        w.pupdr0().floating()
+    });
+}
+
+// sets up an ADC for 16 bit, single conversions of one channel
+fn setup_adc(devices: &Peripherals, adc: &adc3::RegisterBlock, channel: u8) {
+    // bring the ADC out of deep power down, and start its voltage regulator
+    adc.cr.modify(|_, w| {
+        w.deeppwd().power_up()
+    });
+    adc.cr.modify(|_, w| {
+        w.advregen().enabled()
+    });
+    // the regulator needs 10us to start up
+    let t0 = micros_with_devices(devices);
+    while micros_with_devices(devices) - t0 < 10 {}
+
+    // calibrate offset and linearity for single ended inputs
+    adc.cr.modify(|_, w| {
+        w
+            .adcaldif().single_ended()
+            .adcallin().linearity()
+    });
+    adc.cr.modify(|_, w| {
+        w.adcal().calibration()
+    });
+    while adc.cr.read().adcal().is_calibration() {}
+
+    // use a long sample time, since both the thermistor divider and the temperature sensor have a
+    // fairly high source impedance
+    adc.cfgr.modify(|_, w| {
+        w
+            .res().sixteen_bit()
+            .cont().single()
+            .ovrmod().overwrite()
+    });
+    adc.pcsel.modify(|r, w| unsafe {
+        w.pcsel().bits(r.pcsel().bits() | (1 << channel))
+    });
+    // both of the channels we use get the same sample time, so just set it for both on either ADC
+    adc.smpr2.modify(|_, w| {
+        w
+            .smp16().cycles387_5()
+            .smp18().cycles387_5()
+    });
+    adc.sqr1.modify(|_, w| {
+        w
+            .l().variant(0)
+            .sq1().variant(channel)
+    });
+
+    // and finally enable it
+    adc.isr.write(|w| w.adrdy().clear());
+    adc.cr.modify(|_, w| {
+        w.aden().enabled()
+    });
+    while adc.isr.read().adrdy().is_not_ready() {}
+}
+
+// returns None if the ADC doesn't finish a conversion in time, rather than hanging the caller
+fn convert(devices: &Peripherals, adc: &adc3::RegisterBlock) -> Option<u16> {
+    adc.cr.modify(|_, w| {
+        w.adstart().start_conversion()
+    });
+    let t0 = micros_with_devices(devices);
+    while adc.isr.read().eoc().is_not_complete() {
+        if micros_with_devices(devices).saturating_sub(t0) >= CONVERSION_TIMEOUT_US {
+            return None;
+        }
+    }
+    Some(adc.dr.read().rdata().bits())
+}
+
+pub fn get_raw(devices: &mut Peripherals) -> Option<u16> {
+    convert(devices, &devices.ADC1)
+}
+
+pub fn get_temperature_c(devices: &mut Peripherals, thermistor: &ThermistorConfig) -> Option<f32> {
+    get_raw(devices).and_then(|raw| raw_to_temperature_c(raw, thermistor))
+}
+
+// None if the reading is pinned near a rail, so a disconnected thermistor counts as an unknown
+// temperature rather than a very cold one
+pub fn raw_to_temperature_c(raw: u16, thermistor: &ThermistorConfig) -> Option<f32> {
+    let ratio = raw as f32 / ADC_FULL_SCALE;
+    if !(THERMISTOR_RAIL_MARGIN..=1.0 - THERMISTOR_RAIL_MARGIN).contains(&ratio) {
+        return None;
+    }
+    let resistance = thermistor.series_resistance_ohms * ratio / (1.0 - ratio);
+    let ln_r = libm::logf(resistance);
+    let inverse_t = thermistor.a + thermistor.b * ln_r + thermistor.c * ln_r * ln_r * ln_r;
+    Some(1.0 / inverse_t - KELVIN_OFFSET)
+}
+
+pub fn get_die_temperature(devices: &mut Peripherals) -> Option<f32> {
+    let raw = convert(devices, &devices.ADC3)?;
+    let (cal1, cal2) = unsafe { (TS_CAL1.read_volatile() as f32, TS_CAL2.read_volatile() as f32) };
+    Some((TS_CAL2_TEMP_C - TS_CAL1_TEMP_C) / (cal2 - cal1) * (raw as f32 - cal1) + TS_CAL1_TEMP_C)
+}
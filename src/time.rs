@@ -41,22 +41,40 @@ pub fn init() {
     });
 }
 
+/*
+    Reads the millisecond count from TIM5 and the 100ns ticks into that millisecond from TIM3 as one
+    consistent pair. TIM3 rolling over is what clocks TIM5, so TIM5 is read on both sides of TIM3, and the
+    read is retried if it ticked in between, rather than pairing one millisecond's TIM3 count with the
+    next millisecond's TIM5 count and coming out a millisecond off.
+*/
+fn read_counters(devices: &Peripherals) -> (u64, u64) {
+    loop {
+        let millis = devices.TIM5.cnt.read().cnt().bits();
+        let ticks = devices.TIM3.cnt.read().cnt().bits();
+        if devices.TIM5.cnt.read().cnt().bits() == millis {
+            return (millis as u64, ticks as u64);
+        }
+    }
+}
+
 pub fn nanos() -> u64 {
     with_devices(|devices, _| {
-        (devices.TIM3.cnt.read().cnt().bits() as u64 * 100).wrapping_add( 
-            devices.TIM5.cnt.read().cnt().bits() as u64 * 1_000_000
-        )
+        let (millis, ticks) = read_counters(devices);
+        (ticks * 100).wrapping_add(millis * 1_000_000)
     })
 }
 
 pub fn micros() -> u64 {
     with_devices(|devices, _| {
-        (devices.TIM3.cnt.read().cnt().bits() as u64 / 10).wrapping_add(
-            devices.TIM5.cnt.read().cnt().bits() as u64 * 1000
-        )
+        micros_with_devices(devices)
     })
 }
 
+pub fn micros_with_devices(devices: &Peripherals) -> u64 {
+    let (millis, ticks) = read_counters(devices);
+    (ticks / 10).wrapping_add(millis * 1000)
+}
+
 pub fn millis() -> u64 {
     with_devices(|devices, _| {
         devices.TIM5.cnt.read().cnt().bits() as u64
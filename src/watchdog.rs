@@ -0,0 +1,52 @@
+#![allow(unused)]
+
+use stm32h7::stm32h753::Peripherals;
+
+use crate::device_access::with_devices_mut;
+
+/*
+Independent watchdog, clocked from the 32 kHz LSI. If the main loop ever stops kicking it, the MCU
+resets, and the init path brings the HRTIM outputs back up in their idle state, so a hang can't
+leave the bridge driven.
+
+The main loop only kicks it once per burst, but the waits between bursts kick it as they go, so the
+timeout only has to cover the longest stretch without a kick, which is a burst. The burst loop runs
+for the full TOTAL_TIME_US whatever the on-time backstop does to the outputs, so main asserts at
+compile time that a burst fits within qcw::ABSOLUTE_MAX_ON_TIME_US, and the timeout sits a little
+above that, with room for the LSI running fast.
+*/
+
+// LSI / 32 gives a 1 kHz watchdog clock, so the reload value is in milliseconds
+const WATCHDOG_TIMEOUT_MS: u16 = 60;
+
+pub fn init() {
+    with_devices_mut(|devices, _| {
+        init_with_devices(devices)
+    })
+}
+
+pub fn init_with_devices(devices: &mut Peripherals) {
+    // starting the watchdog also starts the LSI
+    devices.IWDG.kr.write(|w| w.key().start());
+    devices.IWDG.kr.write(|w| w.key().enable());
+    devices.IWDG.pr.write(|w| w.pr().divide_by32());
+    devices.IWDG.rlr.write(|w| w.rl().variant(WATCHDOG_TIMEOUT_MS));
+    // wait for the new prescaler and reload values to make it across to the LSI domain
+    loop {
+        let sr_read = devices.IWDG.sr.read();
+        if sr_read.pvu().bit_is_clear() && sr_read.rvu().bit_is_clear() {
+            break;
+        }
+    }
+    devices.IWDG.kr.write(|w| w.key().reset());
+}
+
+pub fn kick() {
+    with_devices_mut(|devices, _| {
+        kick_with_devices(devices);
+    })
+}
+
+pub fn kick_with_devices(devices: &mut Peripherals) {
+    devices.IWDG.kr.write(|w| w.key().reset());
+}